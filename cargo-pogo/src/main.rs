@@ -0,0 +1,281 @@
+//! `cargo pogo build` and `cargo pogo run`
+//!
+//! Builds the project with cargo while recording which rlib every dependency
+//! resolved to. The result is written next to each executable so POGO can pass
+//! the same `--extern` arguments when compiling functions at run-time.
+
+use pogo::link::{LinkInfo, LINK_INFO_EXTENSION};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Flags that change how cargo resolves the dependency graph, these have to be
+/// given to `cargo metadata` as well as the build
+const RESOLVE_FLAGS: &[&str] = &["--manifest-path", "--features", "-F"];
+const RESOLVE_SWITCHES: &[&str] = &[
+    "--all-features",
+    "--no-default-features",
+    "--offline",
+    "--locked",
+    "--frozen",
+];
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    // When run as `cargo pogo` the subcommand name is passed along as well
+    if args.first().map(String::as_str) == Some("pogo") {
+        args.remove(0);
+    }
+
+    if args.is_empty() {
+        usage();
+    }
+    let subcommand = args.remove(0);
+
+    let result = match subcommand.as_str() {
+        "build" => build(&args),
+        "run" => {
+            // Everything after `--` is for the program, not the build
+            let build_args: Vec<String> = args
+                .iter()
+                .take_while(|arg| *arg != "--")
+                .cloned()
+                .collect();
+            build(&build_args).and_then(|()| {
+                let status = Command::new(cargo()).arg("run").args(&args).status()?;
+                std::process::exit(status.code().unwrap_or(1));
+            })
+        }
+        _ => usage(),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: cargo pogo <build|run> [cargo options]");
+    std::process::exit(2);
+}
+
+fn cargo() -> String {
+    std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string())
+}
+
+/// An executable produced by the build
+struct Executable {
+    package_id: String,
+    kind: Vec<String>,
+    path: PathBuf,
+    features: Vec<String>,
+}
+
+fn build(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let deps = resolve_dependencies(args)?;
+
+    let mut child = Command::new(cargo())
+        .arg("build")
+        .arg("--message-format=json-render-diagnostics")
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut libraries: HashMap<String, (String, PathBuf)> = HashMap::new();
+    let mut executables = Vec::new();
+
+    let stdout = child.stdout.take().unwrap();
+    for line in std::io::BufReader::new(stdout).lines() {
+        let message: Value = match serde_json::from_str(&line?) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+
+        let package_id = message["package_id"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let kind = string_list(&message["target"]["kind"]);
+
+        if let Some(path) = message["executable"].as_str() {
+            executables.push(Executable {
+                package_id,
+                kind,
+                path: PathBuf::from(path),
+                features: string_list(&message["features"]),
+            });
+            continue;
+        }
+
+        let is_proc_macro = kind.iter().any(|kind| kind == "proc-macro");
+        let is_lib = is_proc_macro || kind.iter().any(|kind| kind == "lib" || kind == "rlib");
+        if !is_lib {
+            continue;
+        }
+
+        let library = string_list(&message["filenames"]).into_iter().find(|file| {
+            if is_proc_macro {
+                file.ends_with(std::env::consts::DLL_SUFFIX)
+            } else {
+                file.ends_with(".rlib")
+            }
+        });
+        if let Some(library) = library {
+            let crate_name = message["target"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .replace('-', "_");
+            libraries.insert(package_id, (crate_name, PathBuf::from(library)));
+        }
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    for exe in executables {
+        let info = link_info(&exe, &deps, &libraries);
+
+        let mut path = exe.path.into_os_string();
+        path.push(".");
+        path.push(LINK_INFO_EXTENSION);
+        info.save(&path)?;
+    }
+
+    Ok(())
+}
+
+/// A dependency edge from `cargo metadata`'s resolve graph
+struct Dependency {
+    /// Name the crate is referred to by in the source, after renames
+    name: String,
+    package_id: String,
+    /// Dependency kinds, `None` is a normal dependency
+    kinds: Vec<Option<String>>,
+}
+
+fn resolve_dependencies(
+    args: &[String],
+) -> Result<HashMap<String, Vec<Dependency>>, Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(cargo());
+    cmd.args(["metadata", "--format-version", "1"]);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if RESOLVE_SWITCHES.contains(&arg.as_str()) {
+            cmd.arg(arg);
+        } else if RESOLVE_FLAGS.contains(&arg.as_str()) {
+            cmd.arg(arg);
+            cmd.args(args.next());
+        } else if RESOLVE_FLAGS
+            .iter()
+            .any(|flag| arg.starts_with(&format!("{}=", flag)))
+        {
+            cmd.arg(arg);
+        }
+    }
+
+    let output = cmd.stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err("cargo metadata failed".into());
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout)?;
+
+    let mut deps = HashMap::new();
+    for node in metadata["resolve"]["nodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let node_deps = node["deps"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|dep| Dependency {
+                name: dep["name"].as_str().unwrap_or_default().to_string(),
+                package_id: dep["pkg"].as_str().unwrap_or_default().to_string(),
+                kinds: dep["dep_kinds"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|kind| kind["kind"].as_str().map(str::to_string))
+                    .collect(),
+            })
+            .collect();
+
+        deps.insert(
+            node["id"].as_str().unwrap_or_default().to_string(),
+            node_deps,
+        );
+    }
+
+    Ok(deps)
+}
+
+fn link_info(
+    exe: &Executable,
+    deps: &HashMap<String, Vec<Dependency>>,
+    libraries: &HashMap<String, (String, PathBuf)>,
+) -> LinkInfo {
+    // Examples, tests and benches can use dev-dependencies too
+    let dev = exe.kind.iter().any(|kind| kind != "bin");
+
+    let mut info = LinkInfo::default();
+    let mut search_dirs = BTreeSet::new();
+
+    // The package's own library target is available to its executables
+    if let Some((name, path)) = libraries.get(&exe.package_id) {
+        info.externs.push((name.clone(), path.clone()));
+    }
+
+    for dep in deps.get(&exe.package_id).into_iter().flatten() {
+        let usable = dep.kinds.iter().any(|kind| match kind.as_deref() {
+            None => true,
+            Some("dev") => dev,
+            _ => false,
+        });
+        if !usable {
+            continue;
+        }
+
+        if let Some((_, path)) = libraries.get(&dep.package_id) {
+            info.externs.push((dep.name.clone(), path.clone()));
+        }
+    }
+
+    // Transitive dependencies are found through the directories of the rlibs
+    for (_, path) in libraries.values() {
+        if let Some(dir) = path.parent() {
+            search_dirs.insert(dir.to_path_buf());
+        }
+    }
+    info.search_paths = search_dirs
+        .into_iter()
+        .map(|dir| format!("dependency={}", dir.display()))
+        .collect();
+
+    info.cfgs = exe
+        .features
+        .iter()
+        .map(|feature| format!("feature=\"{}\"", feature))
+        .collect();
+
+    info
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_str().map(str::to_string))
+        .collect()
+}
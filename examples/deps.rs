@@ -0,0 +1,31 @@
+use pogo::pogo;
+
+#[pogo::include]
+const BITS_SET: [u8; 16] = [0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4];
+
+#[pogo::include]
+fn nibble_count(nibble: u32) -> u32 {
+    BITS_SET[(nibble & 0xF) as usize] as u32
+}
+
+#[pogo(deps(nibble_count, BITS_SET))]
+fn popcount(mut n: u32) -> u32 {
+    let mut count = 0;
+    while n != 0 {
+        count += nibble_count(n);
+        n >>= 4;
+    }
+    count
+}
+
+fn main() {
+    pogo::init_all("./ex_wrk").unwrap();
+
+    let mut total = 0;
+    for i in 0..10_000u32 {
+        total += popcount(i.wrapping_mul(2_654_435_761));
+        std::thread::sleep(std::time::Duration::from_micros(500));
+    }
+
+    println!("{}", total);
+}
@@ -0,0 +1,27 @@
+use pogo::pogo;
+
+#[pogo(instantiate(u32, u64))]
+fn mul_add<T: Copy + std::ops::Mul<Output = T> + std::ops::Add<Output = T>>(a: T, b: T, c: T) -> T {
+    a * b + c
+}
+
+#[pogo(instantiate((u64, 4), (u64, 8)))]
+fn wrapping_total<T: Copy + Into<u64>, const N: usize>(xs: &[T; N]) -> u64 {
+    xs.iter().fold(0u64, |acc, x| acc.wrapping_add((*x).into()))
+}
+
+fn main() {
+    pogo::init_all("./ex_wrk").unwrap();
+
+    let mut total: u64 = 0;
+    for i in 0..10_000u32 {
+        total = total.wrapping_add(mul_add(i, 3, 1) as u64);
+        total = total.wrapping_add(mul_add(i as u64, 5, 7));
+        total = total.wrapping_add(wrapping_total(&[i as u64; 4]));
+        // Not in the instantiation list, always runs natively
+        total = total.wrapping_add(mul_add(i as u16, 2, 2) as u64);
+        std::thread::sleep(std::time::Duration::from_micros(500));
+    }
+
+    println!("{}", total);
+}
@@ -1,6 +1,9 @@
+// The run-time crate is built from this source, `is_multiple_of` would need Rust 1.87
+#![allow(clippy::manual_is_multiple_of)]
+
 use pogo::pogo;
 
-#[pogo]
+#[pogo(threshold = 2_000, opt_level = 3)]
 fn is_even(n: u32) -> bool {
     n % 2 == 0
 }
@@ -19,7 +22,7 @@ fn pcg_rand(state: &mut u64) -> u32 {
 }
 
 fn main() {
-    pogo::init("./ex_wrk", &[(&__pogo_info_is_even, &__pogo_ctx_is_even)]).unwrap();
+    pogo::init_all("./ex_wrk").unwrap();
 
     let mut state: u64 = 292092009882829;
 
@@ -0,0 +1,45 @@
+use pogo::pogo;
+
+#[pogo]
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[pogo(threshold = 2_000)]
+impl Point {
+    pub fn new(x: f64, y: f64) -> Self {
+        Point { x, y }
+    }
+
+    pub fn dot(&self, other: &Self) -> f64 {
+        self.x * other.x + self.y * other.y
+    }
+
+    #[pogo(opt_level = 3)]
+    pub fn scale(&mut self, by: f64) {
+        self.x *= by;
+        self.y *= by;
+    }
+
+    pub fn into_sum(self) -> f64 {
+        self.x + self.y
+    }
+}
+
+fn main() {
+    pogo::init_all("./ex_wrk").unwrap();
+
+    let mut total = 0.0;
+    for i in 0..10_000u32 {
+        let mut p = Point::new(i as f64, 1.0);
+        p.scale(0.5);
+        total += p.dot(&Point::new(1.0, 2.0));
+        total += p.into_sum();
+        std::thread::sleep(std::time::Duration::from_micros(500));
+    }
+
+    println!("{}", total);
+}
@@ -0,0 +1,29 @@
+use pogo::pogo;
+
+#[pogo]
+fn manhattan(&(ax, ay): &(i64, i64), &[bx, by]: &[i64; 2], _: u8) -> i64 {
+    (ax - bx).abs() + (ay - by).abs()
+}
+
+#[pogo]
+fn count_down(mut n: u32) -> u32 {
+    let mut steps = 0;
+    while n > 0 {
+        n /= 3;
+        steps += 1;
+    }
+    steps
+}
+
+fn main() {
+    pogo::init_all("./ex_wrk").unwrap();
+
+    let mut total = 0;
+    for i in 0..10_000i64 {
+        total += manhattan(&(i, 2 * i), &[0, i], 0);
+        total += count_down(i as u32) as i64;
+        std::thread::sleep(std::time::Duration::from_micros(500));
+    }
+
+    println!("{}", total);
+}
@@ -0,0 +1,342 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{GenericArgument, Ident, LitInt, LitStr, Token};
+
+/// The parsed contents of `#[pogo(...)]`
+#[derive(Clone, Default)]
+pub struct PogoArgs {
+    pub edition: Option<LitStr>,
+    pub threshold: Option<LitInt>,
+    pub opt_level: Option<OptLevelArg>,
+    pub codegen: Option<syn::Path>,
+    pub verify: Option<LitInt>,
+    pub group: Option<syn::Path>,
+    pub instantiate: Option<Vec<Instantiation>>,
+    pub deps: Option<Vec<syn::Path>>,
+}
+
+/// One entry of `instantiate(...)`, either a single generic argument or a
+/// parenthesized list with one argument per generic parameter
+#[derive(Clone)]
+pub struct Instantiation {
+    pub span: proc_macro2::Span,
+    pub args: Vec<GenericArgument>,
+}
+
+impl Parse for Instantiation {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let span = input.span();
+        if input.peek(syn::token::Paren) {
+            let content;
+            syn::parenthesized!(content in input);
+            let args = Punctuated::<GenericArgument, Token![,]>::parse_terminated(&content)?;
+            Ok(Instantiation {
+                span,
+                args: args.into_iter().collect(),
+            })
+        } else {
+            Ok(Instantiation {
+                span,
+                args: vec![input.parse()?],
+            })
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum OptLevelArg {
+    Int(LitInt),
+    Str(LitStr),
+}
+
+impl Parse for PogoArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = PogoArgs::default();
+
+        let items = Punctuated::<PogoArg, Token![,]>::parse_terminated(input)?;
+        for item in items {
+            match item {
+                PogoArg::Edition(key, value) => set_once(&mut args.edition, &key, value)?,
+                PogoArg::Threshold(key, value) => set_once(&mut args.threshold, &key, value)?,
+                PogoArg::OptLevel(key, value) => set_once(&mut args.opt_level, &key, value)?,
+                PogoArg::Codegen(key, value) => set_once(&mut args.codegen, &key, value)?,
+                PogoArg::Verify(key, value) => set_once(&mut args.verify, &key, value)?,
+                PogoArg::Group(key, value) => set_once(&mut args.group, &key, value)?,
+                PogoArg::Instantiate(key, value) => set_once(&mut args.instantiate, &key, value)?,
+                PogoArg::Deps(key, value) => set_once(&mut args.deps, &key, value)?,
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+fn set_once<T>(slot: &mut Option<T>, key: &Ident, value: T) -> syn::Result<()> {
+    if slot.is_some() {
+        return Err(syn::Error::new(
+            key.span(),
+            format!("duplicate `{}` argument", key),
+        ));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+enum PogoArg {
+    Edition(Ident, LitStr),
+    Threshold(Ident, LitInt),
+    OptLevel(Ident, OptLevelArg),
+    Codegen(Ident, syn::Path),
+    Verify(Ident, LitInt),
+    Group(Ident, syn::Path),
+    Instantiate(Ident, Vec<Instantiation>),
+    Deps(Ident, Vec<syn::Path>),
+}
+
+impl Parse for PogoArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse()?;
+
+        if key == "instantiate" {
+            let content;
+            syn::parenthesized!(content in input);
+            let list = Punctuated::<Instantiation, Token![,]>::parse_terminated(&content)?;
+            if list.is_empty() {
+                return Err(syn::Error::new(
+                    key.span(),
+                    "`instantiate` needs at least one instantiation",
+                ));
+            }
+            return Ok(PogoArg::Instantiate(key, list.into_iter().collect()));
+        }
+
+        if key == "deps" {
+            let content;
+            syn::parenthesized!(content in input);
+            let list = Punctuated::<syn::Path, Token![,]>::parse_terminated(&content)?;
+            return Ok(PogoArg::Deps(key, list.into_iter().collect()));
+        }
+
+        input.parse::<Token![=]>()?;
+
+        match key.to_string().as_str() {
+            "edition" => {
+                let value: LitStr = input.parse()?;
+                match value.value().as_str() {
+                    "2015" | "2018" | "2021" => Ok(PogoArg::Edition(key, value)),
+                    _ => Err(syn::Error::new(
+                        value.span(),
+                        "expected one of \"2015\", \"2018\" or \"2021\"",
+                    )),
+                }
+            }
+            "threshold" => {
+                let value: LitInt = input.parse()?;
+                value.base10_parse::<usize>()?;
+                Ok(PogoArg::Threshold(key, value))
+            }
+            "opt_level" => {
+                if input.peek(LitStr) {
+                    let value: LitStr = input.parse()?;
+                    match value.value().as_str() {
+                        "0" | "1" | "2" | "3" | "s" | "z" => {
+                            Ok(PogoArg::OptLevel(key, OptLevelArg::Str(value)))
+                        }
+                        _ => Err(syn::Error::new(
+                            value.span(),
+                            "expected an opt-level of 0, 1, 2, 3, \"s\" or \"z\"",
+                        )),
+                    }
+                } else {
+                    let value: LitInt = input.parse()?;
+                    match value.base10_parse::<u8>()? {
+                        0..=3 => Ok(PogoArg::OptLevel(key, OptLevelArg::Int(value))),
+                        _ => Err(syn::Error::new(
+                            value.span(),
+                            "expected an opt-level of 0, 1, 2, 3, \"s\" or \"z\"",
+                        )),
+                    }
+                }
+            }
+            "codegen" => Ok(PogoArg::Codegen(key, input.parse()?)),
+            "verify" => {
+                let value: LitInt = input.parse()?;
+                value.base10_parse::<usize>()?;
+                Ok(PogoArg::Verify(key, value))
+            }
+            "group" => Ok(PogoArg::Group(key, input.parse()?)),
+            _ => Err(syn::Error::new(
+                key.span(),
+                format!(
+                    "unknown pogo argument `{}`, expected one of `edition`, `threshold`, `opt_level`, `codegen`, `verify`, `group`, `instantiate` or `deps`",
+                    key
+                ),
+            )),
+        }
+    }
+}
+
+impl PogoArgs {
+    pub fn is_empty(&self) -> bool {
+        self.edition.is_none()
+            && self.threshold.is_none()
+            && self.opt_level.is_none()
+            && self.codegen.is_none()
+            && self.verify.is_none()
+            && self.group.is_none()
+            && self.instantiate.is_none()
+            && self.deps.is_none()
+    }
+
+    /// Fill any argument that wasn't given from `defaults`
+    pub fn or(self, defaults: &PogoArgs) -> PogoArgs {
+        PogoArgs {
+            edition: self.edition.or_else(|| defaults.edition.clone()),
+            threshold: self.threshold.or_else(|| defaults.threshold.clone()),
+            opt_level: self.opt_level.or_else(|| defaults.opt_level.clone()),
+            codegen: self.codegen.or_else(|| defaults.codegen.clone()),
+            verify: self.verify.or_else(|| defaults.verify.clone()),
+            group: self.group.or_else(|| defaults.group.clone()),
+            instantiate: self.instantiate.or_else(|| defaults.instantiate.clone()),
+            deps: self.deps.or_else(|| defaults.deps.clone()),
+        }
+    }
+
+    /// The `pogo::Edition` the runtime compiler should use for this function
+    pub fn edition_tokens(&self) -> TokenStream2 {
+        match self.edition.as_ref().map(LitStr::value).as_deref() {
+            Some("2015") => quote!(pogo::Edition::Rust2015),
+            Some("2021") => quote!(pogo::Edition::Rust2021),
+            _ => quote!(pogo::Edition::Rust2018),
+        }
+    }
+
+    pub fn threshold_tokens(&self) -> TokenStream2 {
+        match &self.threshold {
+            Some(threshold) => quote!(::core::option::Option::Some(#threshold)),
+            None => quote!(::core::option::Option::None),
+        }
+    }
+
+    pub fn opt_level_tokens(&self) -> TokenStream2 {
+        let level = match &self.opt_level {
+            Some(OptLevelArg::Int(lit)) => lit.base10_digits().to_string(),
+            Some(OptLevelArg::Str(lit)) => lit.value(),
+            None => return quote!(::core::option::Option::None),
+        };
+
+        let variant = match level.as_str() {
+            "0" => quote!(O0),
+            "1" => quote!(O1),
+            "2" => quote!(O2),
+            "3" => quote!(O3),
+            "s" => quote!(Size),
+            _ => quote!(SizeMin),
+        };
+        quote!(::core::option::Option::Some(pogo::OptLevel::#variant))
+    }
+
+    /// The `pogo::CodegenProfile` constant given with `codegen = PATH`
+    pub fn codegen_tokens(&self) -> TokenStream2 {
+        match &self.codegen {
+            Some(codegen) => quote!(::core::option::Option::Some(#codegen)),
+            None => quote!(::core::option::Option::None),
+        }
+    }
+
+    /// Calls to verify the optimized library for, 0 when it isn't verified
+    pub fn verify_calls(&self) -> usize {
+        self.verify
+            .as_ref()
+            .and_then(|verify| verify.base10_parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Paths to the source constants of the items listed in `deps(...)`
+    pub fn deps_tokens(&self) -> Vec<TokenStream2> {
+        self.deps
+            .iter()
+            .flatten()
+            .map(|path| {
+                let src_path = crate::include::src_const_path(path);
+                quote!(#src_path)
+            })
+            .collect()
+    }
+
+    /// The group used by the plain (non `_with_group`) wrapper
+    pub fn group_tokens(&self) -> TokenStream2 {
+        match &self.group {
+            Some(group) => quote!(#group),
+            None => quote!(pogo::Global),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(args: &str) -> String {
+        match syn::parse_str::<PogoArgs>(args) {
+            Ok(_) => panic!("`{}` parsed", args),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_every_argument() {
+        let args: PogoArgs = syn::parse_str(
+            r#"edition = "2021", threshold = 100, opt_level = "s", codegen = SMALL, verify = 5,
+            group = my::Group, instantiate(u32, (u64, 4)), deps(helper, TABLE)"#,
+        )
+        .unwrap();
+        assert_eq!(args.threshold.as_ref().unwrap().base10_digits(), "100");
+        assert_eq!(
+            args.opt_level_tokens().to_string(),
+            quote!(::core::option::Option::Some(pogo::OptLevel::Size)).to_string()
+        );
+        assert_eq!(args.verify_calls(), 5);
+        let instantiate = args.instantiate.unwrap();
+        assert_eq!(instantiate.len(), 2);
+        assert_eq!(instantiate[1].args.len(), 2);
+        assert_eq!(args.deps.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn empty_arguments() {
+        assert!(syn::parse_str::<PogoArgs>("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicate_argument() {
+        assert_eq!(
+            parse_err("threshold = 1, threshold = 2"),
+            "duplicate `threshold` argument"
+        );
+        assert_eq!(parse_err("deps(a), deps(b)"), "duplicate `deps` argument");
+    }
+
+    #[test]
+    fn unknown_argument() {
+        assert!(parse_err("treshold = 1").starts_with("unknown pogo argument `treshold`"));
+    }
+
+    #[test]
+    fn opt_level_out_of_range() {
+        let expected = "expected an opt-level of 0, 1, 2, 3, \"s\" or \"z\"";
+        assert_eq!(parse_err("opt_level = 4"), expected);
+        assert_eq!(parse_err("opt_level = \"fast\""), expected);
+        assert!(syn::parse_str::<PogoArgs>("opt_level = 3").is_ok());
+        assert!(syn::parse_str::<PogoArgs>("opt_level = \"z\"").is_ok());
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(parse_err("edition = \"2024\"").starts_with("expected one of"));
+        parse_err("threshold = -1");
+        parse_err("instantiate()");
+    }
+}
@@ -0,0 +1,398 @@
+use crate::generics::Substitute;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::visit_mut::VisitMut;
+use syn::{FnArg, GenericArgument, Ident, PathArguments, ReturnType, Type};
+
+/// Path to the FFI types on the host
+pub fn host_types() -> TokenStream2 {
+    quote!(pogo::ffi)
+}
+
+/// Path to the FFI types in the run-time library, `init` writes a copy of them
+/// into every library
+pub fn lib_types() -> TokenStream2 {
+    quote!(crate::__pogo_ffi)
+}
+
+/// How a value crosses between the host and the run-time library, everything
+/// goes through `extern "C"` functions so it has to be FFI-safe on the way
+#[derive(Clone)]
+pub enum Marshal {
+    /// Already FFI-safe, passed as it is
+    Direct(Type),
+    Str,
+    Slice(Type),
+    SliceMut(Type),
+    String,
+    Vec(Type),
+    Option(Box<Marshal>),
+    Result(Box<Marshal>, Box<Marshal>),
+}
+
+impl Marshal {
+    /// Decide how a type is passed, the common types that can't be lowered to
+    /// anything FFI-safe are rejected here and rustc catches the rest through
+    /// [`FfiSignature::lint_check`]
+    pub fn of(ty: &Type) -> syn::Result<Marshal> {
+        let unsupported = |reason: &str| {
+            Err(syn::Error::new_spanned(
+                ty,
+                format!(
+                    "#[pogo] function signatures have to be FFI-safe: {}",
+                    reason
+                ),
+            ))
+        };
+
+        match ty {
+            Type::Paren(paren) => Marshal::of(&paren.elem),
+            Type::Group(group) => Marshal::of(&group.elem),
+            Type::Reference(reference) => match reference.elem.as_ref() {
+                Type::Path(path) if path.path.is_ident("str") => {
+                    if reference.mutability.is_some() {
+                        unsupported("`&mut str` isn't supported")
+                    } else {
+                        Ok(Marshal::Str)
+                    }
+                }
+                Type::Slice(slice) => {
+                    let elem = element(&slice.elem)?;
+                    if reference.mutability.is_some() {
+                        Ok(Marshal::SliceMut(elem))
+                    } else {
+                        Ok(Marshal::Slice(elem))
+                    }
+                }
+                Type::TraitObject(_) => unsupported("trait objects aren't FFI-safe"),
+                // A reference to a sized type is just a pointer
+                _ => Ok(Marshal::Direct(ty.clone())),
+            },
+            Type::Tuple(tuple) if !tuple.elems.is_empty() => {
+                unsupported("tuples aren't FFI-safe, use a `#[pogo]` struct with `#[repr(C)]`")
+            }
+            Type::Array(_) => {
+                unsupported("arrays can't be passed by value through the C ABI, use a reference")
+            }
+            Type::TraitObject(_) => unsupported("trait objects aren't FFI-safe"),
+            Type::Path(path) if path.qself.is_none() => {
+                let last = path.path.segments.last().unwrap();
+                let args = type_arguments(&last.arguments);
+                match (last.ident.to_string().as_str(), args.as_slice()) {
+                    ("String", []) => Ok(Marshal::String),
+                    ("Vec", [elem]) => Ok(Marshal::Vec(element(elem)?)),
+                    ("Option", [inner]) => Ok(Marshal::Option(Box::new(Marshal::of(inner)?))),
+                    ("Result", [ok, err]) => Ok(Marshal::Result(
+                        Box::new(Marshal::of(ok)?),
+                        Box::new(Marshal::of(err)?),
+                    )),
+                    _ => Ok(Marshal::Direct(ty.clone())),
+                }
+            }
+            _ => Ok(Marshal::Direct(ty.clone())),
+        }
+    }
+
+    fn is_direct(&self) -> bool {
+        matches!(self, Marshal::Direct(_))
+    }
+
+    /// The FFI-safe type the value is passed as
+    fn ffi_type(&self, types: &TokenStream2) -> TokenStream2 {
+        match self {
+            Marshal::Direct(ty) => quote!(#ty),
+            Marshal::Str => quote!(#types::FfiStr),
+            Marshal::Slice(elem) => quote!(#types::FfiSlice<#elem>),
+            Marshal::SliceMut(elem) => quote!(#types::FfiSliceMut<#elem>),
+            Marshal::String => quote!(#types::FfiString),
+            Marshal::Vec(elem) => quote!(#types::FfiVec<#elem>),
+            Marshal::Option(inner) => {
+                let inner = inner.ffi_type(types);
+                quote!(#types::FfiOption<#inner>)
+            }
+            Marshal::Result(ok, err) => {
+                let ok = ok.ffi_type(types);
+                let err = err.ffi_type(types);
+                quote!(#types::FfiResult<#ok, #err>)
+            }
+        }
+    }
+
+    /// Convert `value` to its FFI-safe type before the call
+    fn lower(&self, types: &TokenStream2, value: TokenStream2) -> TokenStream2 {
+        match self {
+            Marshal::Direct(_) => value,
+            Marshal::Str => quote!(#types::FfiStr::new(#value)),
+            Marshal::Slice(_) => quote!(#types::FfiSlice::new(#value)),
+            Marshal::SliceMut(_) => quote!(#types::FfiSliceMut::new(#value)),
+            Marshal::String => quote!(#types::FfiString::new(#value)),
+            Marshal::Vec(_) => quote!(#types::FfiVec::new(#value)),
+            Marshal::Option(inner) if inner.is_direct() => {
+                quote!(#types::FfiOption::new(#value))
+            }
+            Marshal::Option(inner) => {
+                let inner = inner.lower(types, quote!(__pogo_value));
+                quote!(#types::FfiOption::new(#value.map(|__pogo_value| #inner)))
+            }
+            Marshal::Result(ok, err) => {
+                let mut value = value;
+                if !ok.is_direct() {
+                    let ok = ok.lower(types, quote!(__pogo_value));
+                    value = quote!(#value.map(|__pogo_value| #ok));
+                }
+                if !err.is_direct() {
+                    let err = err.lower(types, quote!(__pogo_value));
+                    value = quote!(#value.map_err(|__pogo_value| #err));
+                }
+                quote!(#types::FfiResult::new(#value))
+            }
+        }
+    }
+
+    /// Rebuild the value on the other side of the call, this has to be used in
+    /// an `unsafe` block
+    fn raise(&self, value: TokenStream2) -> TokenStream2 {
+        match self {
+            Marshal::Direct(_) => value,
+            Marshal::Str => quote!(#value.as_str()),
+            Marshal::Slice(_) => quote!(#value.as_slice()),
+            Marshal::SliceMut(_) => quote!(#value.as_mut_slice()),
+            Marshal::String => quote!(#value.into_string()),
+            Marshal::Vec(_) => quote!(#value.into_vec()),
+            Marshal::Option(inner) if inner.is_direct() => quote!(#value.into_option()),
+            Marshal::Option(inner) => {
+                let inner = inner.raise(quote!(__pogo_value));
+                quote!(#value.into_option().map(|__pogo_value| #inner))
+            }
+            Marshal::Result(ok, err) => {
+                let mut value = quote!(#value.into_result());
+                if !ok.is_direct() {
+                    let ok = ok.raise(quote!(__pogo_value));
+                    value = quote!(#value.map(|__pogo_value| #ok));
+                }
+                if !err.is_direct() {
+                    let err = err.raise(quote!(__pogo_value));
+                    value = quote!(#value.map_err(|__pogo_value| #err));
+                }
+                value
+            }
+        }
+    }
+
+    /// Types rustc has to check for FFI-safety, elements behind the pointers
+    /// of slices and `Vec`s aren't checked through the FFI type
+    fn lint_types(&self, types: &TokenStream2, out: &mut Vec<TokenStream2>) {
+        out.push(self.ffi_type(types));
+        self.element_types(out);
+    }
+
+    fn element_types(&self, out: &mut Vec<TokenStream2>) {
+        match self {
+            Marshal::Slice(elem) | Marshal::SliceMut(elem) | Marshal::Vec(elem) => {
+                out.push(quote!(#elem))
+            }
+            Marshal::Option(inner) => inner.element_types(out),
+            Marshal::Result(ok, err) => {
+                ok.element_types(out);
+                err.element_types(out);
+            }
+            Marshal::Direct(_) | Marshal::Str | Marshal::String => {}
+        }
+    }
+
+    fn substitute(&mut self, subst: &mut Substitute) {
+        match self {
+            Marshal::Direct(ty) | Marshal::Slice(ty) | Marshal::SliceMut(ty) | Marshal::Vec(ty) => {
+                subst.visit_type_mut(ty)
+            }
+            Marshal::Option(inner) => inner.substitute(subst),
+            Marshal::Result(ok, err) => {
+                ok.substitute(subst);
+                err.substitute(subst);
+            }
+            Marshal::Str | Marshal::String => {}
+        }
+    }
+}
+
+/// Elements of slices and `Vec`s are moved across as they are
+fn element(ty: &Type) -> syn::Result<Type> {
+    match Marshal::of(ty)? {
+        Marshal::Direct(ty) => Ok(ty),
+        _ => Err(syn::Error::new_spanned(
+            ty,
+            "#[pogo] function signatures have to be FFI-safe: elements of slices and `Vec`s are passed as they are, so they have to be FFI-safe themselves",
+        )),
+    }
+}
+
+fn type_arguments(args: &PathArguments) -> Vec<&Type> {
+    match args {
+        PathArguments::AngleBracketed(args) => args
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The arguments and return value of a function as they cross into the
+/// run-time library, the receiver of a method is handled separately
+#[derive(Clone)]
+pub struct FfiSignature {
+    args: Vec<(Ident, Marshal)>,
+    output: Option<Marshal>,
+}
+
+impl FfiSignature {
+    /// `inputs` have to bind every argument to a plain name, like the wrapper's
+    pub fn new<'a>(
+        inputs: impl IntoIterator<Item = &'a FnArg>,
+        output: &ReturnType,
+    ) -> syn::Result<FfiSignature> {
+        let mut args = Vec::new();
+        for arg in inputs {
+            if let FnArg::Typed(pat_type) = arg {
+                let name = match pat_type.pat.as_ref() {
+                    syn::Pat::Ident(ident) => ident.ident.clone(),
+                    other => {
+                        return Err(syn::Error::new_spanned(
+                            other,
+                            "expected the argument to be bound to a name",
+                        ))
+                    }
+                };
+                args.push((name, Marshal::of(&pat_type.ty)?));
+            }
+        }
+
+        let output = match output {
+            ReturnType::Default => None,
+            ReturnType::Type(_, ty) => Some(Marshal::of(ty)?),
+        };
+
+        Ok(FfiSignature { args, output })
+    }
+
+    /// Replace generic parameters or `Self` in every type
+    pub fn substitute(&mut self, subst: &mut Substitute) {
+        for (_, marshal) in self.args.iter_mut() {
+            marshal.substitute(subst);
+        }
+        if let Some(output) = self.output.as_mut() {
+            output.substitute(subst);
+        }
+    }
+
+    /// `name: Type` for every argument, as the exported function takes them
+    pub fn inputs(&self, types: &TokenStream2) -> Vec<TokenStream2> {
+        self.args
+            .iter()
+            .map(|(name, marshal)| {
+                let ty = marshal.ffi_type(types);
+                quote!(#name: #ty)
+            })
+            .collect()
+    }
+
+    pub fn arg_types(&self, types: &TokenStream2) -> Vec<TokenStream2> {
+        self.args
+            .iter()
+            .map(|(_, marshal)| marshal.ffi_type(types))
+            .collect()
+    }
+
+    pub fn output(&self, types: &TokenStream2) -> TokenStream2 {
+        match &self.output {
+            Some(output) => {
+                let ty = output.ffi_type(types);
+                quote!(-> #ty)
+            }
+            None => quote!(),
+        }
+    }
+
+    /// The exported function's return type, left uninitialized when the call
+    /// panicked
+    pub fn shim_output(&self, types: &TokenStream2) -> TokenStream2 {
+        let ty = match &self.output {
+            Some(output) => output.ffi_type(types),
+            None => quote!(()),
+        };
+        quote!(-> ::core::mem::MaybeUninit<#ty>)
+    }
+
+    /// The body of the exported function around `call`, a panic can't unwind
+    /// into the host so it's caught into the `__pogo_panic` argument instead
+    pub fn catch_panic(&self, types: &TokenStream2, call: TokenStream2) -> TokenStream2 {
+        let call = self.lower_output(types, call);
+        quote!(#types::catch_panic(__pogo_panic, || unsafe { #call }))
+    }
+
+    /// The arguments converted for passing them to the exported function
+    pub fn lower_args(&self, types: &TokenStream2) -> Vec<TokenStream2> {
+        self.args
+            .iter()
+            .map(|(name, marshal)| marshal.lower(types, quote!(#name)))
+            .collect()
+    }
+
+    /// The arguments rebuilt inside the exported function, needs `unsafe`
+    pub fn raise_args(&self) -> Vec<TokenStream2> {
+        self.args
+            .iter()
+            .map(|(name, marshal)| marshal.raise(quote!(#name)))
+            .collect()
+    }
+
+    /// Convert the result of `call` for returning it from the exported function
+    pub fn lower_output(&self, types: &TokenStream2, call: TokenStream2) -> TokenStream2 {
+        match &self.output {
+            Some(output) => output.lower(types, call),
+            None => call,
+        }
+    }
+
+    /// Rebuild the result of calling the exported function, needs `unsafe`
+    pub fn raise_output(&self, call: TokenStream2) -> TokenStream2 {
+        match &self.output {
+            Some(output) => output.raise(call),
+            None => call,
+        }
+    }
+
+    /// An uncallable `extern "C"` function taking every type that crosses the
+    /// boundary, rustc's `improper_ctypes_definitions` lint then catches the
+    /// types [`Marshal::of`] can't judge from their name, like structs
+    /// without `#[repr(C)]`
+    pub fn lint_check<'a>(
+        &self,
+        name: &Ident,
+        lifetimes: impl IntoIterator<Item = &'a syn::LifetimeDef>,
+    ) -> TokenStream2 {
+        let check_name = format_ident!("__pogo_ffi_check_{}", name);
+        let lifetimes: Vec<_> = lifetimes.into_iter().collect();
+        let types = host_types();
+
+        let mut checked = Vec::new();
+        for (_, marshal) in &self.args {
+            marshal.lint_types(&types, &mut checked);
+        }
+        let output = self.output(&types);
+        if let Some(output) = &self.output {
+            output.element_types(&mut checked);
+        }
+
+        quote! {
+            #[deny(improper_ctypes_definitions)]
+            #[allow(dead_code, non_snake_case)]
+            extern "C" fn #check_name<#(#lifetimes),*>(#(_: #checked),*) #output {
+                unreachable!()
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use syn::visit_mut::VisitMut;
+use syn::{Expr, GenericArgument, GenericParam, Generics, Ident, Type};
+
+/// The type and const parameters that have to be instantiated before the
+/// function can be exported from the shared object
+pub fn instantiable_params(generics: &Generics) -> Vec<&GenericParam> {
+    generics
+        .params
+        .iter()
+        .filter(|param| !matches!(param, GenericParam::Lifetime(_)))
+        .collect()
+}
+
+pub fn param_ident(param: &GenericParam) -> &Ident {
+    match param {
+        GenericParam::Type(ty) => &ty.ident,
+        GenericParam::Const(cnst) => &cnst.ident,
+        GenericParam::Lifetime(lt) => &lt.lifetime.ident,
+    }
+}
+
+/// Adds a `'static` bound to every type parameter, the wrappers pick the
+/// instantiation to call by comparing `TypeId`s
+pub fn require_static(generics: &Generics) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!('static));
+    }
+    generics
+}
+
+/// Replaces generic parameters with the concrete arguments of one instantiation
+pub struct Substitute {
+    types: HashMap<Ident, Type>,
+    consts: HashMap<Ident, Expr>,
+}
+
+impl Substitute {
+    pub fn new(params: &[&GenericParam], args: &[GenericArgument]) -> syn::Result<Self> {
+        let mut types = HashMap::new();
+        let mut consts = HashMap::new();
+
+        for (param, arg) in params.iter().zip(args) {
+            match (param, arg) {
+                (GenericParam::Type(param), GenericArgument::Type(ty)) => {
+                    types.insert(param.ident.clone(), ty.clone());
+                }
+                (GenericParam::Const(param), GenericArgument::Const(expr)) => {
+                    consts.insert(param.ident.clone(), expr.clone());
+                }
+                // A bare identifier or path is parsed as a type, but could
+                // name a constant as well
+                (GenericParam::Const(param), GenericArgument::Type(ty)) => {
+                    consts.insert(param.ident.clone(), syn::parse_quote!({ #ty }));
+                }
+                (GenericParam::Type(param), _) => {
+                    return Err(syn::Error::new_spanned(
+                        arg,
+                        format!("expected a type for generic parameter `{}`", param.ident),
+                    ))
+                }
+                (param, _) => {
+                    return Err(syn::Error::new_spanned(
+                        arg,
+                        format!(
+                            "expected a constant for generic parameter `{}`",
+                            param_ident(param)
+                        ),
+                    ))
+                }
+            }
+        }
+
+        Ok(Substitute { types, consts })
+    }
+
+    /// Replaces `Self` with the implemented type, for code moved out of an impl block
+    pub fn self_type(ty: Type) -> Self {
+        let mut types = HashMap::new();
+        types.insert(Ident::new("Self", proc_macro2::Span::call_site()), ty);
+        Substitute {
+            types,
+            consts: HashMap::new(),
+        }
+    }
+}
+
+impl VisitMut for Substitute {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if let Type::Path(path) = ty {
+            if path.qself.is_none() {
+                if let Some(ident) = path.path.get_ident() {
+                    if let Some(concrete) = self.types.get(ident) {
+                        *ty = concrete.clone();
+                        return;
+                    }
+                }
+            }
+        }
+        syn::visit_mut::visit_type_mut(self, ty);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Path(path) = expr {
+            if path.qself.is_none() {
+                if let Some(ident) = path.path.get_ident() {
+                    if let Some(concrete) = self.consts.get(ident) {
+                        *expr = concrete.clone();
+                        return;
+                    }
+                }
+            }
+        }
+        syn::visit_mut::visit_expr_mut(self, expr);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::args::PogoArgs;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{Attribute, Item, Visibility};
+
+/// Path to the `__pogo_src_*` constant generated for the item at `path`
+pub fn src_const_path(path: &syn::Path) -> syn::Path {
+    let mut src_path = path.clone();
+    let last = src_path.segments.last_mut().unwrap();
+    last.ident = format_ident!("__pogo_src_{}", last.ident);
+    src_path
+}
+
+/// Expand `#[pogo::include]`, recording the source of an item so `#[pogo]`
+/// functions can name it in `deps(...)`
+pub fn expand_include(item: Item) -> syn::Result<TokenStream2> {
+    export(item)
+}
+
+/// Expand `#[pogo]` on a type definition, this records the source of the type
+/// so `#[pogo]` impl blocks can compile it into their run-time library
+pub fn expand_type(args: &PogoArgs, item: Item) -> syn::Result<TokenStream2> {
+    if !args.is_empty() {
+        return Err(syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[pogo] on a type definition doesn't take arguments",
+        ));
+    }
+
+    let (ident, generics, attrs) = match &item {
+        Item::Struct(item) => (&item.ident, &item.generics, &item.attrs),
+        Item::Enum(item) => (&item.ident, &item.generics, &item.attrs),
+        Item::Union(item) => (&item.ident, &item.generics, &item.attrs),
+        _ => unreachable!(),
+    };
+
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            generics,
+            "#[pogo] doesn't support generic types",
+        ));
+    }
+    if !has_stable_layout(attrs) {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "types used by #[pogo] methods need `#[repr(C)]` or `#[repr(transparent)]` so their layout matches in the run-time compiled library",
+        ));
+    }
+
+    // `repr(C)` only fixes the order of the fields, rustc's lint checks that
+    // every field can cross into the library as well
+    let check_name = format_ident!("__pogo_ffi_check_{}", ident);
+    let ffi_check = quote! {
+        #[deny(improper_ctypes_definitions)]
+        #[allow(dead_code, non_snake_case)]
+        extern "C" fn #check_name(_: #ident) {}
+    };
+
+    let exported = export(item)?;
+    Ok(quote! {
+        #exported
+        #ffi_check
+    })
+}
+
+/// Emit the item along with a constant holding the source it is compiled from
+/// in the run-time library, where it lives at the crate root
+fn export(item: Item) -> syn::Result<TokenStream2> {
+    let mut lib_item = item.clone();
+    let (vis, ident) = match &mut lib_item {
+        Item::Struct(item) => {
+            for field in item.fields.iter_mut() {
+                field.vis = Visibility::Inherited;
+            }
+            (&mut item.vis, &item.ident)
+        }
+        Item::Union(item) => {
+            for field in item.fields.named.iter_mut() {
+                field.vis = Visibility::Inherited;
+            }
+            (&mut item.vis, &item.ident)
+        }
+        Item::Enum(item) => (&mut item.vis, &item.ident),
+        Item::Fn(item) => (&mut item.vis, &item.sig.ident),
+        Item::Const(item) => (&mut item.vis, &item.ident),
+        Item::Static(item) => (&mut item.vis, &item.ident),
+        Item::Type(item) => (&mut item.vis, &item.ident),
+        other => {
+            return Err(syn::Error::new_spanned(
+                other,
+                "#[pogo::include] supports functions, constants, statics and type definitions",
+            ))
+        }
+    };
+
+    let src_name = format_ident!("__pogo_src_{}", ident);
+    let host_vis = vis.clone();
+    // Public in the library so unused helpers don't warn and nothing is
+    // private to the exported functions
+    *vis = syn::parse_quote!(pub);
+    let lib_src = quote!(#lib_item).to_string();
+
+    Ok(quote! {
+        #item
+
+        #[doc(hidden)]
+        #[allow(non_upper_case_globals)]
+        #host_vis const #src_name: &str = #lib_src;
+    })
+}
+
+fn has_stable_layout(attrs: &[Attribute]) -> bool {
+    const STABLE_REPRS: &[&str] = &[
+        "C",
+        "transparent",
+        "u8",
+        "u16",
+        "u32",
+        "u64",
+        "usize",
+        "i8",
+        "i16",
+        "i32",
+        "i64",
+        "isize",
+    ];
+
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .filter_map(|attr| attr.parse_meta().ok())
+        .any(|meta| match meta {
+            syn::Meta::List(list) => list.nested.iter().any(|nested| match nested {
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) => {
+                    STABLE_REPRS.iter().any(|repr| path.is_ident(repr))
+                }
+                _ => false,
+            }),
+            _ => false,
+        })
+}
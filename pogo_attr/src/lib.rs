@@ -1,122 +1,562 @@
 extern crate proc_macro;
 use proc_macro::TokenStream;
-use quote::quote;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
 use syn::{parse_macro_input, ItemFn};
 
-#[proc_macro_attribute]
-pub fn pogo(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(item as ItemFn);
+mod args;
+mod ffi;
+mod generics;
+mod include;
+mod methods;
 
-    let input_src_string = quote!(#input).to_string();
+use args::PogoArgs;
 
-    let function_name = input.sig.ident;
-    let function_inputs = input.sig.inputs;
-    let return_type = input.sig.output;
-    let function_body = input.block;
-    let native_func_name = quote::format_ident!("__pogo_native_{}", function_name);
+/// Mark a function for online profile guided optimization
+///
+/// Accepted arguments:
+/// - `edition = "2015" | "2018" | "2021"`: edition used to compile the function at run-time
+/// - `threshold = N`: profiled executions before optimizing, overrides the group's `PGO_EXEC_COUNT`
+/// - `opt_level = 0..=3 | "s" | "z"`: `-Copt-level` for the run-time compiled versions,
+///   overrides the one in the codegen profile
+/// - `codegen = PATH`: a `const` `pogo::CodegenProfile` for the run-time compiled
+///   versions, replaces the group's `CODEGEN` and the configured default
+/// - `verify = N`: run the natively compiled version alongside the optimized library
+///   for its first `N` calls and reject the library if any result differs. The
+///   return type has to implement `PartialEq` and `Debug` and the arguments
+///   `Clone`, the function runs twice per call meanwhile so it can't have side
+///   effects or take `&mut` arguments
+/// - `group = Path`: the `PogoGroup` used when calling the function without `_with_group`
+/// - `deps(helper, TABLE, path::to::Type)`: items marked `#[pogo::include]` whose
+///   source is compiled into the run-time library along with the function
+/// - `instantiate(A, B, (C, 4), ...)`: required for generic functions, the generic
+///   arguments to compile at run-time. Functions with several generic parameters
+///   take a parenthesized list per instantiation. Other instantiations always run
+///   the natively compiled version. The instantiation is picked by `TypeId`, so
+///   the wrapper functions require `'static` type parameters.
+///
+/// Argument and return types have to be FFI-safe since the run-time compiled
+/// version is called through an `extern "C"` function. `&str`, slices, `String`,
+/// `Vec`, `Option` and `Result` are converted through the types in `pogo::ffi`.
+/// A panic can't unwind out of the library, it's caught there and resumed on the
+/// host with the panic message as a `String` payload.
+///
+/// On an inherent `impl` block every method is optimized, the arguments on the
+/// block are the defaults for each method and a `#[pogo(...)]` on a method
+/// overrides them. The type itself needs `#[pogo]` on its definition so its
+/// source can be compiled into the run-time library, and every field of it has to
+/// be FFI-safe. A method taking `self` by value needs the type to be `Copy`.
+#[proc_macro_attribute]
+pub fn pogo(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as PogoArgs);
+    let item = parse_macro_input!(item as syn::Item);
 
-    let native_function = quote! {
-        pub(crate) fn #native_func_name(#function_inputs) #return_type {
-            #function_body
+    let result = match item {
+        syn::Item::Fn(func) => expand_fn(&args, &func, None).map(|exp| {
+            let Expansion { items, assoc } = exp;
+            quote!(#items #assoc)
+        }),
+        syn::Item::Impl(block) => methods::expand_impl(&args, block),
+        syn::Item::Struct(_) | syn::Item::Enum(_) | syn::Item::Union(_) => {
+            include::expand_type(&args, item)
         }
+        other => Err(syn::Error::new_spanned(
+            other,
+            "#[pogo] can only be applied to functions, inherent impl blocks and type definitions",
+        )),
     };
 
-    let mut type_args: syn::punctuated::Punctuated<Box<syn::Type>, syn::token::Comma> =
+    match result {
+        Ok(tokens) => TokenStream::from(tokens),
+        Err(err) => TokenStream::from(err.to_compile_error()),
+    }
+}
+
+/// Make an item available to the run-time compiled version of `#[pogo]`
+/// functions that list it in `deps(...)`
+///
+/// Works on functions, constants, statics and type definitions. Anything the
+/// item itself uses has to be listed in `deps(...)` as well.
+#[proc_macro_attribute]
+pub fn include(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return TokenStream::from(
+            syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "#[pogo::include] doesn't take arguments",
+            )
+            .to_compile_error(),
+        );
+    }
+    let item = parse_macro_input!(item as syn::Item);
+
+    match include::expand_include(item) {
+        Ok(tokens) => TokenStream::from(tokens),
+        Err(err) => TokenStream::from(err.to_compile_error()),
+    }
+}
+
+/// The generated code for one optimized function
+struct Expansion {
+    /// Statics that have to live at module level
+    items: TokenStream2,
+    /// The wrapper functions, these go in the impl block for methods
+    assoc: TokenStream2,
+}
+
+fn expand_fn(
+    args: &PogoArgs,
+    input: &ItemFn,
+    method: Option<&methods::MethodCtx>,
+) -> syn::Result<Expansion> {
+    let function_name = &input.sig.ident;
+    let function_inputs = &input.sig.inputs;
+    let return_type = &input.sig.output;
+    let function_body = &input.block;
+    let generics = &input.sig.generics;
+    let native_func_name = quote::format_ident!("__pogo_native_{}", function_name);
+
+    let mut receiver = None;
+    let mut arg_names: syn::punctuated::Punctuated<syn::Ident, syn::token::Comma> =
+        syn::punctuated::Punctuated::new();
+
+    // The user's argument patterns only matter inside the function body, the
+    // wrappers and exported shims take every argument by a plain name instead
+    let mut wrapper_inputs: syn::punctuated::Punctuated<syn::FnArg, syn::token::Comma> =
         syn::punctuated::Punctuated::new();
 
-    for arg in function_inputs.iter() {
+    for (idx, arg) in function_inputs.iter().enumerate() {
         match arg {
-            syn::FnArg::Receiver(_) => panic!("Not supported"),
+            syn::FnArg::Receiver(recv) => match method {
+                Some(_) => {
+                    receiver = Some(recv);
+
+                    let mut recv = recv.clone();
+                    if recv.reference.is_none() {
+                        recv.mutability = None;
+                    }
+                    wrapper_inputs.push(syn::FnArg::Receiver(recv));
+                }
+                None => {
+                    return Err(syn::Error::new_spanned(
+                        recv,
+                        "#[pogo] methods need `#[pogo]` on the enclosing impl block",
+                    ))
+                }
+            },
             syn::FnArg::Typed(pat_type) => {
-                type_args.push_value(pat_type.ty.clone());
+                if let syn::Type::ImplTrait(_) = pat_type.ty.as_ref() {
+                    return Err(syn::Error::new_spanned(
+                        &pat_type.ty,
+                        "#[pogo] doesn't support `impl Trait` arguments, use a named generic parameter and `instantiate(...)`",
+                    ));
+                }
+
+                let name = match pat_type.pat.as_ref() {
+                    syn::Pat::Ident(ident) if ident.ident == "self" => {
+                        return Err(syn::Error::new_spanned(
+                            pat_type,
+                            "#[pogo] only supports `self`, `&self` and `&mut self` receivers",
+                        ))
+                    }
+                    // Keep the user's name where we can so the wrapper's
+                    // signature still reads well in the docs
+                    syn::Pat::Ident(ident) if ident.by_ref.is_none() && ident.subpat.is_none() => {
+                        ident.ident.clone()
+                    }
+                    _ => quote::format_ident!("__pogo_arg{}", idx),
+                };
+
+                let ty = &pat_type.ty;
+                wrapper_inputs.push(syn::parse_quote!(#name: #ty));
+                arg_names.push(name);
             }
         }
     }
 
-    let mut arg_names: syn::punctuated::Punctuated<syn::Ident, syn::token::Comma> =
-        syn::punctuated::Punctuated::new();
+    // Verifying calls the function twice, which would apply any mutation twice
+    let verify = args.verify_calls();
+    if verify > 0 {
+        let mut_arg = function_inputs.iter().find(|arg| match arg {
+            syn::FnArg::Receiver(recv) => recv.reference.is_some() && recv.mutability.is_some(),
+            syn::FnArg::Typed(pat_type) => matches!(
+                pat_type.ty.as_ref(),
+                syn::Type::Reference(reference) if reference.mutability.is_some()
+            ),
+        });
+        if let Some(arg) = mut_arg {
+            return Err(syn::Error::new_spanned(
+                arg,
+                "`verify` can't be used on functions taking `&mut` arguments",
+            ));
+        }
+    }
 
-    for arg in function_inputs.iter() {
-        match arg {
-            syn::FnArg::Receiver(_) => panic!("Not supported"),
-            syn::FnArg::Typed(pat_type) => match &pat_type.pat.as_ref() {
-                syn::Pat::Ident(ident) => arg_names.push_value(ident.ident.clone()),
-                _ => panic!("Not supported"),
-            },
+    // Everything crosses into the shared object through `extern "C"` shims,
+    // with std types lowered to FFI-safe ones on the way
+    let signature = ffi::FfiSignature::new(&wrapper_inputs, return_type)?;
+    let lib_types = ffi::lib_types();
+    let host_types = ffi::host_types();
+    let lifetime_params: Vec<_> = generics.lifetimes().collect();
+
+    // Generic functions can only be exported once they are instantiated, so the
+    // shared object gets one symbol per entry of `instantiate(...)`
+    let params = generics::instantiable_params(generics);
+    let param_idents: Vec<_> = params.iter().map(|p| generics::param_ident(p)).collect();
+    let native_turbofish = if params.is_empty() {
+        quote!()
+    } else {
+        quote!(::<#(#param_idents),*>)
+    };
+
+    if let (Some(_), Some(param)) = (method, params.first()) {
+        return Err(syn::Error::new_spanned(
+            param,
+            "#[pogo] doesn't support generic methods",
+        ));
+    }
+
+    // How the host calls into its own items, methods go through `self` or `Self`
+    let (native_call, group_call) = {
+        let group_func_name = quote::format_ident!("{}_with_group", function_name);
+        match (method, receiver) {
+            (None, _) => (quote!(#native_func_name), quote!(#group_func_name)),
+            (Some(_), Some(_)) => (
+                quote!(self.#native_func_name),
+                quote!(self.#group_func_name),
+            ),
+            (Some(_), None) => (
+                quote!(Self::#native_func_name),
+                quote!(Self::#group_func_name),
+            ),
         }
+    };
+
+    let str_func_name = match method {
+        Some(method) => format!("{}::{}", method.type_name, function_name),
+        None => function_name.to_string(),
+    };
+    let ident_base = match method {
+        Some(method) => format!("{}_{}", method.type_name, function_name),
+        None => function_name.to_string(),
+    };
+
+    let (dylib_src, symbols, symbol_select, ffi_check) = if let Some(method) = method {
+        let symbol = format!("{}__{}", method.type_name, function_name);
+        let shim = method.shim(&symbol, function_name, receiver, &signature);
+        let impl_src = &method.impl_src;
+
+        let dylib_src = quote! {
+            #impl_src
+            #shim
+        };
+
+        let ffi_check = signature.lint_check(function_name, lifetime_params.iter().copied());
+
+        (dylib_src, vec![symbol], quote!(0), ffi_check)
+    } else if params.is_empty() {
+        if let Some(inst) = args.instantiate.as_ref().and_then(|list| list.first()) {
+            return Err(syn::Error::new(
+                inst.span,
+                "`instantiate` is only valid on generic functions",
+            ));
+        }
+
+        let symbol = format!("__pogo_{}", function_name);
+        let symbol_ident = quote::format_ident!("{}", symbol);
+        let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+        let shim_inputs = signature.inputs(&lib_types);
+        let shim_output = signature.shim_output(&lib_types);
+        let raised_args = signature.raise_args();
+        let call = signature.catch_panic(&lib_types, quote!(#function_name(#(#raised_args),*)));
+
+        let mut lib_fn = input.clone();
+        lib_fn.vis = syn::Visibility::Inherited;
+        let dylib_src = quote! {
+            #lib_fn
+
+            #[no_mangle]
+            #[allow(unused_unsafe)]
+            pub unsafe extern "C" fn #symbol_ident #impl_generics(#(#shim_inputs,)* __pogo_panic: *mut #lib_types::FfiPanic) #shim_output #where_clause {
+                #call
+            }
+        };
+
+        let ffi_check = signature.lint_check(function_name, lifetime_params.iter().copied());
+
+        (dylib_src, vec![symbol], quote!(0), ffi_check)
+    } else {
+        let instantiations = args.instantiate.as_ref().ok_or_else(|| {
+            syn::Error::new_spanned(
+                generics,
+                "generic #[pogo] functions need an `instantiate(...)` list of the concrete generic arguments to compile",
+            )
+        })?;
+
+        let mut generic_fn = input.clone();
+        generic_fn.vis = syn::Visibility::Inherited;
+
+        let mut shims = Vec::with_capacity(instantiations.len());
+        let mut ffi_checks = Vec::with_capacity(instantiations.len());
+        let mut select_arms = Vec::with_capacity(instantiations.len());
+        let mut symbols = Vec::with_capacity(instantiations.len());
+
+        for (idx, inst) in instantiations.iter().enumerate() {
+            if inst.args.len() != params.len() {
+                return Err(syn::Error::new(
+                    inst.span,
+                    format!(
+                        "expected {} generic argument(s) to match `{}`, found {}",
+                        params.len(),
+                        quote!(#generics),
+                        inst.args.len()
+                    ),
+                ));
+            }
+
+            let mut subst = generics::Substitute::new(&params, &inst.args)?;
+            let mut inst_signature = signature.clone();
+            inst_signature.substitute(&mut subst);
+
+            let symbol = format!("__pogo_{}_{}", function_name, idx);
+            let symbol_ident = quote::format_ident!("{}", symbol);
+            let inst_args = &inst.args;
+
+            ffi_checks.push(inst_signature.lint_check(
+                &quote::format_ident!("{}_{}", function_name, idx),
+                lifetime_params.iter().copied(),
+            ));
+
+            let shim_inputs = inst_signature.inputs(&lib_types);
+            let shim_output = inst_signature.shim_output(&lib_types);
+            let raised_args = inst_signature.raise_args();
+            let call = inst_signature.catch_panic(
+                &lib_types,
+                quote!(#function_name::<#(#inst_args),*>(#(#raised_args),*)),
+            );
+
+            shims.push(quote! {
+                #[no_mangle]
+                #[allow(unused_unsafe)]
+                pub unsafe extern "C" fn #symbol_ident(#(#shim_inputs,)* __pogo_panic: *mut #lib_types::FfiPanic) #shim_output {
+                    #call
+                }
+            });
+
+            let conditions = params.iter().zip(inst_args).map(|(param, arg)| {
+                let ident = generics::param_ident(param);
+                match param {
+                    syn::GenericParam::Type(_) => quote! {
+                        ::core::any::TypeId::of::<#ident>() == ::core::any::TypeId::of::<#arg>()
+                    },
+                    _ => quote!(#ident == #arg),
+                }
+            });
+            select_arms.push(quote! {
+                if #(#conditions)&&* {
+                    #idx
+                }
+            });
+            symbols.push(symbol);
+        }
+
+        let dylib_src = quote! {
+            #generic_fn
+            #(#shims)*
+        };
+
+        let symbol_select = quote! {
+            #(#select_arms)else* else {
+                // This instantiation isn't compiled at run-time
+                return #native_call #native_turbofish(#arg_names);
+            }
+        };
+
+        (dylib_src, symbols, symbol_select, quote!(#(#ffi_checks)*))
+    };
+    let dylib_src_string = dylib_src.to_string();
+    let mut deps = args.deps_tokens();
+    if let Some(method) = method {
+        deps.push(method.type_src.clone());
     }
 
-    let vis = input.vis;
-    let group_func_name = quote::format_ident!("{}_with_group", function_name);
-    let ctx_name = quote::format_ident!("__pogo_ctx_{}", function_name);
-    let info_name = quote::format_ident!("__pogo_info_{}", function_name);
+    // The receiver is passed to the shared object as a pointer, a by-value
+    // `self` is moved into the library so it mustn't be dropped here
+    let lib_arg_types = signature.arg_types(&host_types);
+    let lib_output = signature.shim_output(&host_types);
+    let lowered_args = signature.lower_args(&host_types);
+    let (lib_prelude, lib_fn_args, lib_call_args) = match receiver {
+        None => (
+            quote!(),
+            quote!(#(#lib_arg_types,)* *mut #host_types::FfiPanic),
+            quote!(#(#lowered_args,)* &mut __pogo_panic),
+        ),
+        Some(recv) if recv.reference.is_none() => (
+            // The library drops what it's given with its own allocator, only
+            // plain data can be moved into it
+            quote_spanned! {recv.span()=>
+                fn __pogo_self_by_value_needs_copy<T: ::core::marker::Copy>(_: &T) {}
+                __pogo_self_by_value_needs_copy(&self);
+                let mut __pogo_this = ::core::mem::ManuallyDrop::new(self);
+            },
+            quote!(*mut Self, #(#lib_arg_types,)* *mut #host_types::FfiPanic),
+            quote!(&mut *__pogo_this as *mut Self, #(#lowered_args,)* &mut __pogo_panic),
+        ),
+        Some(recv) if recv.mutability.is_some() => (
+            quote!(),
+            quote!(*mut Self, #(#lib_arg_types,)* *mut #host_types::FfiPanic),
+            quote!(self as *mut Self, #(#lowered_args,)* &mut __pogo_panic),
+        ),
+        Some(_) => (
+            quote!(),
+            quote!(*const Self, #(#lib_arg_types,)* *mut #host_types::FfiPanic),
+            quote!(self as *const Self, #(#lowered_args,)* &mut __pogo_panic),
+        ),
+    };
+    // A panic in the library comes back as a message and is resumed here
+    let lib_call = signature.raise_output(quote!({
+        let mut __pogo_panic = #host_types::FfiPanic::new();
+        let __pogo_result = func(#lib_call_args);
+        __pogo_panic.resume(__pogo_result)
+    }));
 
-    let str_func_name = function_name.to_string();
+    // While the optimized library is verified the native version runs on
+    // clones of the arguments and its result is the one returned
+    let verify_call = if verify > 0 {
+        let verify_native_call = match receiver {
+            Some(recv) if recv.reference.is_none() => {
+                quote!(::core::clone::Clone::clone(&self).#native_func_name)
+            }
+            _ => native_call.clone(),
+        };
+        let cloned_args = arg_names
+            .iter()
+            .map(|arg| quote!(::core::clone::Clone::clone(&#arg)));
+        quote! {
+            if group.is_verifying() {
+                let __pogo_native = #verify_native_call #native_turbofish(#(#cloned_args),*);
+                let __pogo_library = unsafe {
+                    let func = ::core::mem::transmute::<*mut ::core::ffi::c_void, unsafe extern "C" fn(#lib_fn_args) #lib_output>(__pogo_func);
+                    #lib_prelude
+                    #lib_call
+                };
+                group.record_verification(ctx, &__pogo_native, &__pogo_library);
+                return __pogo_native;
+            }
+        }
+    } else {
+        quote!()
+    };
 
-    TokenStream::from(quote! {
-        #native_function
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let wrapper_generics = generics::require_static(generics);
+    let (wrapper_impl_generics, _, _) = wrapper_generics.split_for_impl();
+
+    // The group parameter has to come after any lifetime parameters
+    let mut group_generics = wrapper_generics.clone();
+    let lifetime_count = generics.lifetimes().count();
+    group_generics
+        .params
+        .insert(lifetime_count, syn::parse_quote!(Grp: pogo::PogoGroup));
+    let (group_impl_generics, _, _) = group_generics.split_for_impl();
 
+    let native_function = quote! {
+        pub(crate) fn #native_func_name #impl_generics(#function_inputs) #return_type #where_clause
+            #function_body
+    };
+
+    let vis = &input.vis;
+    let group_func_name = quote::format_ident!("{}_with_group", function_name);
+    let ctx_name = quote::format_ident!("__pogo_ctx_{}", ident_base);
+    let info_name = quote::format_ident!("__pogo_info_{}", ident_base);
+    let reg_name = quote::format_ident!("__pogo_reg_{}", ident_base);
+
+    let edition = args.edition_tokens();
+    let threshold = args.threshold_tokens();
+    let opt_level = args.opt_level_tokens();
+    let codegen = args.codegen_tokens();
+    let default_group = args.group_tokens();
+
+    let items = quote! {
         #[allow(non_upper_case_globals)]
         static #ctx_name: pogo::ContextCell = pogo::ContextCell::new();
 
         #[allow(non_upper_case_globals)]
         static #info_name: pogo::PogoFuncDefinition = pogo::PogoFuncDefinition {
-            edition: pogo::Edition::Rust2018,
-            name: #str_func_name,
-            src: #input_src_string,
+            edition: #edition,
+            // Functions of the same name in other modules or crates get their
+            // own working directory
+            name: ::core::concat!(::core::module_path!(), "::", #str_func_name),
+            src: #dylib_src_string,
+            deps: &[#(#deps),*],
+            threshold: #threshold,
+            codegen: #codegen,
+            opt_level: #opt_level,
+            verify: #verify,
+            symbols: &[#(#symbols),*],
+            default_group: pogo::GroupDefinition::of::<#default_group>(),
+        };
+
+        #[pogo::linkme::distributed_slice(pogo::REGISTERED_FUNCTIONS)]
+        #[linkme(crate = pogo::linkme)]
+        #[allow(non_upper_case_globals)]
+        static #reg_name: pogo::Registration = pogo::Registration {
+            info: &#info_name,
+            ctx: &#ctx_name,
         };
+    };
+
+    let assoc = quote! {
+        #native_function
 
-        #vis fn #function_name(#function_inputs) #return_type {
-            #group_func_name::<pogo::Global>(#arg_names)
+        #ffi_check
+
+        #vis fn #function_name #wrapper_impl_generics(#wrapper_inputs) #return_type #where_clause {
+            #group_call::<#default_group, #(#param_idents),*>(#arg_names)
         }
 
-        #vis fn #group_func_name<Grp: pogo::PogoGroup>(#function_inputs) #return_type {
+        #vis fn #group_func_name #group_impl_generics(#wrapper_inputs) #return_type #where_clause {
+            let __pogo_symbol: usize = #symbol_select;
+
             match #ctx_name.get() {
                 Some(ctx) if Grp::USE_PGO => {
-                    match ctx.groups.get(Grp::NAME) {
+                    match ctx.groups.get(Grp::KEY) {
                         Some(group) => {
-                            match &group.pgo_state {
-                                pogo::PgoState::Uninitialized | pogo::PgoState::CompilationFailed => {
-                                    #native_func_name(#arg_names)
-                                }
-                                pogo::PgoState::GatheringData(lib) => {
-                                    if group.pgo_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) >= Grp::PGO_EXEC_COUNT
-                                    {
-                                        pogo::submit_optimization_request(ctx, Grp::NAME);
-                                    }
-
-                                    unsafe {
-                                        let func: libloading::Symbol<unsafe extern fn(#type_args) #return_type> = lib.get(ctx.info.name.as_bytes()).expect("Run-time compiled shared object didn't contain expected function");
-                                        func(#arg_names)
-                                    }
-                                }
-                                pogo::PgoState::Compiling(lib) | pogo::PgoState::Optimized(lib) => unsafe {
-                                    let func: libloading::Symbol<unsafe extern fn(#type_args) #return_type> = lib.get(ctx.info.name.as_bytes()).expect("Run-time compiled shared object didn't contain expected function");
-                                    func(#arg_names)
-                                },
+                            // Keeps the library from being unloaded until the
+                            // call returns
+                            let __pogo_guard = ctx.pin();
+                            // Null until a run-time compiled library is loaded
+                            let __pogo_func = group.funcs.get(__pogo_symbol);
+                            if __pogo_func.is_null() {
+                                return #native_call #native_turbofish(#arg_names);
+                            }
+
+                            group.record_execution(ctx);
+
+                            #verify_call
+
+                            unsafe {
+                                let func = ::core::mem::transmute::<*mut ::core::ffi::c_void, unsafe extern "C" fn(#lib_fn_args) #lib_output>(__pogo_func);
+                                #lib_prelude
+                                #lib_call
                             }
                         }
                         None => {
-                            ctx.groups.upsert(
-                                Grp::NAME,
-                                || {
-                                    pogo::GroupState {
-                                        pgo_state: pogo::PgoState::Uninitialized,
-                                        pgo_count: std::sync::atomic::AtomicUsize::new(0),
-                                    }
-                                },
-                                |_| {
-                                    // The value already existed by the time we got to this branch
-                                    // so don't touch it, someone should have already initialized it
-                                },
-                            );
+                            // Only the call that creates the group compiles it
+                            if let Some(group) = ctx.groups.insert(Grp::KEY, Grp::NAME, ctx.threshold(Grp::PGO_EXEC_COUNT), ctx.codegen(Grp::CODEGEN), ctx.info) {
+                                pogo::submit_initial_request(ctx, group.name);
+                            }
                             // Execute the unoptimized non-tracking version for now
-                            #native_func_name(#arg_names)
+                            #native_call #native_turbofish(#arg_names)
                         }
                     }
                 },
-                _ => #native_func_name(#arg_names),
+                _ => #native_call #native_turbofish(#arg_names),
             }
         }
-    })
+    };
+
+    Ok(Expansion { items, assoc })
 }
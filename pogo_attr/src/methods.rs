@@ -0,0 +1,202 @@
+use crate::args::PogoArgs;
+use crate::ffi::{self, FfiSignature};
+use crate::generics::Substitute;
+use crate::include;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{Attribute, Ident, ImplItem, ItemFn, ItemImpl, Type};
+
+/// What `expand_fn` needs to know about the impl block a method lives in
+pub struct MethodCtx {
+    /// Name of the implemented type, the run-time library defines it at its root
+    pub type_name: Ident,
+    /// Path to the `__pogo_src_*` constant holding the source of the type
+    pub type_src: TokenStream2,
+    /// The impl block as it is compiled into the run-time library
+    pub impl_src: TokenStream2,
+}
+
+impl MethodCtx {
+    /// Generate the exported free function wrapping a method in the run-time
+    /// library, the receiver becomes a pointer argument
+    pub fn shim(
+        &self,
+        symbol: &str,
+        method_name: &Ident,
+        receiver: Option<&syn::Receiver>,
+        signature: &FfiSignature,
+    ) -> TokenStream2 {
+        let type_name = &self.type_name;
+        let mut signature = signature.clone();
+        signature.substitute(&mut Substitute::self_type(syn::parse_quote!(#type_name)));
+
+        let types = ffi::lib_types();
+        let shim_inputs = signature.inputs(&types);
+        let shim_output = signature.shim_output(&types);
+        let args = signature.raise_args();
+
+        let (this_arg, call) = match receiver {
+            None => (quote!(), quote!(#type_name::#method_name(#(#args),*))),
+            Some(recv) if recv.reference.is_none() => (
+                quote!(this: *mut #type_name,),
+                quote!(::core::ptr::read(this).#method_name(#(#args),*)),
+            ),
+            Some(recv) if recv.mutability.is_some() => (
+                quote!(this: *mut #type_name,),
+                quote!((*this).#method_name(#(#args),*)),
+            ),
+            Some(_) => (
+                quote!(this: *const #type_name,),
+                quote!((*this).#method_name(#(#args),*)),
+            ),
+        };
+        let call = signature.catch_panic(&types, call);
+
+        let symbol = format_ident!("{}", symbol);
+        quote! {
+            #[no_mangle]
+            #[allow(non_snake_case, unused_unsafe)]
+            pub unsafe extern "C" fn #symbol(#this_arg #(#shim_inputs,)* __pogo_panic: *mut #types::FfiPanic) #shim_output {
+                #call
+            }
+        }
+    }
+}
+
+fn is_pogo_attr(attr: &Attribute) -> bool {
+    attr.path.is_ident("pogo")
+        || (attr.path.segments.len() == 2
+            && attr.path.segments[0].ident == "pogo"
+            && attr.path.segments[1].ident == "pogo")
+}
+
+/// Expand `#[pogo]` on an inherent impl block, every method in it is optimized
+pub fn expand_impl(args: &PogoArgs, mut block: ItemImpl) -> syn::Result<TokenStream2> {
+    if let Some((_, path, _)) = &block.trait_ {
+        return Err(syn::Error::new_spanned(
+            path,
+            "#[pogo] only supports inherent impl blocks",
+        ));
+    }
+    if let Some(inst) = args.instantiate.as_ref().and_then(|list| list.first()) {
+        return Err(syn::Error::new(
+            inst.span,
+            "`instantiate` is only valid on generic functions",
+        ));
+    }
+    if !block.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &block.generics,
+            "#[pogo] doesn't support generic impl blocks",
+        ));
+    }
+
+    let type_path = match block.self_ty.as_ref() {
+        Type::Path(path) if path.qself.is_none() => path.path.clone(),
+        other => {
+            return Err(syn::Error::new_spanned(
+                other,
+                "#[pogo] impl blocks have to be for a named type",
+            ))
+        }
+    };
+    let last = type_path.segments.last().unwrap();
+    if !last.arguments.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &last.arguments,
+            "#[pogo] doesn't support generic types",
+        ));
+    }
+    let type_name = last.ident.clone();
+
+    let type_src = include::src_const_path(&type_path);
+
+    // Gather the per-method arguments, removing the attributes as we go since
+    // they aren't expanded on their own inside the block
+    let mut method_args = Vec::new();
+    for item in block.items.iter_mut() {
+        if let ImplItem::Method(method) = item {
+            let mut overrides = PogoArgs::default();
+            for attr in method.attrs.iter().filter(|attr| is_pogo_attr(attr)) {
+                if !attr.tokens.is_empty() {
+                    overrides = attr.parse_args()?;
+                }
+            }
+            method.attrs.retain(|attr| !is_pogo_attr(attr));
+            method_args.push(overrides.or(args));
+        }
+    }
+
+    // Every method's library contains the whole block, so it needs what any
+    // of the methods lists in `deps(...)`
+    let mut all_deps: Vec<syn::Path> = Vec::new();
+    let mut seen = Vec::new();
+    for dep in method_args
+        .iter()
+        .flat_map(|args| args.deps.iter().flatten())
+    {
+        let key = quote!(#dep).to_string();
+        if !seen.contains(&key) {
+            seen.push(key);
+            all_deps.push(dep.clone());
+        }
+    }
+    if !all_deps.is_empty() {
+        for args in method_args.iter_mut() {
+            args.deps = Some(all_deps.clone());
+        }
+    }
+
+    // The copy compiled at run-time lives at the root of its own crate
+    let mut lib_block = block.clone();
+    *lib_block.self_ty = syn::parse_quote!(#type_name);
+    for item in lib_block.items.iter_mut() {
+        match item {
+            ImplItem::Method(method) => method.vis = syn::Visibility::Inherited,
+            ImplItem::Const(cnst) => cnst.vis = syn::Visibility::Inherited,
+            _ => {}
+        }
+    }
+
+    // Each method gets its own library, so the others are unused there
+    let ctx = MethodCtx {
+        type_name,
+        type_src: quote!(#type_src),
+        impl_src: quote! {
+            #[allow(dead_code)]
+            #lib_block
+        },
+    };
+
+    let mut items = Vec::new();
+    let mut host_items = Vec::new();
+    let mut method_args = method_args.into_iter();
+
+    for item in block.items.drain(..) {
+        match item {
+            ImplItem::Method(method) => {
+                let func = ItemFn {
+                    attrs: method.attrs,
+                    vis: method.vis,
+                    sig: method.sig,
+                    block: Box::new(method.block),
+                };
+                let exp = crate::expand_fn(&method_args.next().unwrap(), &func, Some(&ctx))?;
+                items.push(exp.items);
+                host_items.push(exp.assoc);
+            }
+            other => host_items.push(quote!(#other)),
+        }
+    }
+
+    let attrs = &block.attrs;
+    let self_ty = &block.self_ty;
+    Ok(quote! {
+        #(#attrs)*
+        impl #self_ty {
+            #(#host_items)*
+        }
+
+        #(#items)*
+    })
+}
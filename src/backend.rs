@@ -0,0 +1,274 @@
+//! The compiler used to build the run-time libraries
+
+use crate::toolchain::{self, ToolchainError, ToolchainReport};
+use crate::trace::event;
+use crate::{CodegenProfile, LinkInfo, PogoError, PogoFuncDefinition};
+use once_cell::sync::OnceCell;
+use std::error::Error;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+pub type BackendResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// Set with `--cfg` when building the instrumented library, it then exports
+/// the functions POGO uses to write out the profile while the library is
+/// still loaded
+pub const INSTRUMENTED_CFG: &str = "pogo_instrumented";
+
+/// One library to build for a function's optimization group
+#[derive(Debug)]
+pub struct CompileJob<'a> {
+    pub info: &'static PogoFuncDefinition,
+    pub group_name: &'a str,
+    pub codegen: CodegenProfile,
+    /// Source of the library crate
+    pub src: &'a Path,
+    /// Where the shared object has to be written
+    pub output: &'a Path,
+}
+
+/// Builds the instrumented and optimized libraries
+///
+/// Every step runs on the POGO worker thread, a step returning an error marks
+/// the group as failed and it keeps using the natively compiled function.
+pub trait CompilerBackend: Send + Sync {
+    /// Check everything needed is available, run by `init` before anything is
+    /// compiled so a broken setup is reported immediately
+    fn check_toolchain(&self) -> BackendResult {
+        Ok(())
+    }
+
+    /// Build a library that writes profiling data into `profile_dir`, with
+    /// [`INSTRUMENTED_CFG`] set
+    fn instrument(&self, job: &CompileJob, profile_dir: &Path) -> BackendResult;
+
+    /// Merge the raw profiles in `profile_dir` into a single file at `output`
+    fn merge_profiles(&self, profile_dir: &Path, output: &Path) -> BackendResult;
+
+    /// Build the library using the merged profile at `profile`
+    fn optimize(&self, job: &CompileJob, profile: &Path) -> BackendResult;
+
+    /// Identifies the compiler and any settings affecting its output, used as
+    /// part of the artifact cache key. `None` disables the cache.
+    ///
+    /// This has to cover the machine as well when the backend builds for the
+    /// native CPU, like [`CodegenProfile::release`](crate::CodegenProfile::release)
+    /// asks for, since the working directory can be shared between hosts.
+    fn identity(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// The default backend, `rustc` for compiling and `llvm-profdata` for merging
+/// profiles
+#[derive(Clone, Debug)]
+pub struct RustcBackend {
+    rustc: PathBuf,
+    llvm_profdata: Option<PathBuf>,
+    link_info: LinkInfo,
+    args: Vec<String>,
+    toolchain: OnceCell<ToolchainReport>,
+    /// [`LinkInfo::fingerprint`], computed once since it reads every extern
+    link_fingerprint: OnceCell<String>,
+    /// What `-Ctarget-cpu=native` resolves to, `None` if rustc couldn't say
+    native_cpu: OnceCell<Option<String>>,
+}
+
+impl Default for RustcBackend {
+    fn default() -> Self {
+        RustcBackend {
+            rustc: PathBuf::from("rustc"),
+            llvm_profdata: None,
+            link_info: LinkInfo::default(),
+            args: Vec::new(),
+            toolchain: OnceCell::new(),
+            link_fingerprint: OnceCell::new(),
+            native_cpu: OnceCell::new(),
+        }
+    }
+}
+
+impl RustcBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use a different `rustc`, like a specific toolchain's or a wrapper script
+    pub fn rustc<P: Into<PathBuf>>(mut self, rustc: P) -> Self {
+        self.rustc = rustc.into();
+        self
+    }
+
+    /// Use a specific `llvm-profdata` instead of searching for one
+    pub fn llvm_profdata<P: Into<PathBuf>>(mut self, llvm_profdata: P) -> Self {
+        self.llvm_profdata = Some(llvm_profdata.into());
+        self
+    }
+
+    /// Link information for the crates the functions may use
+    pub fn link_info(mut self, link_info: LinkInfo) -> Self {
+        self.link_info = link_info;
+        self.link_fingerprint = OnceCell::new();
+        self
+    }
+
+    /// Extra arguments for every `rustc` invocation
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Find the tools this backend uses, see [`check_toolchain`](crate::check_toolchain)
+    pub fn toolchain(&self) -> Result<&ToolchainReport, ToolchainError> {
+        self.toolchain
+            .get_or_try_init(|| toolchain::discover(&self.rustc, self.llvm_profdata.as_deref()))
+    }
+
+    /// The CPU and target features rustc builds for with
+    /// `-Ctarget-cpu=native` on this machine
+    fn native_cpu(&self) -> Option<&str> {
+        self.native_cpu
+            .get_or_init(|| {
+                let output = Command::new(&self.rustc)
+                    .args(["-Ctarget-cpu=native", "--print", "cfg"])
+                    .args(["--print", "target-cpus"])
+                    .output()
+                    .ok()
+                    .filter(|output| output.status.success())?;
+                let report = String::from_utf8_lossy(&output.stdout);
+                let native = report
+                    .lines()
+                    .filter(|line| {
+                        line.starts_with("target_feature=")
+                            || line.trim_start().starts_with("native")
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Some(native)
+            })
+            .as_deref()
+    }
+
+    fn rustc_command(&self, job: &CompileJob) -> Command {
+        let mut cmd = Command::new(&self.rustc);
+        job.info.add_rustc_args(&mut cmd);
+        job.codegen.add_rustc_args(&mut cmd);
+        self.link_info.add_rustc_args(&mut cmd);
+        cmd.args(&self.args);
+        cmd.arg("-o");
+        cmd.arg(job.output);
+        cmd.arg(job.src);
+        cmd
+    }
+}
+
+impl CompilerBackend for RustcBackend {
+    fn check_toolchain(&self) -> BackendResult {
+        self.toolchain()?;
+        Ok(())
+    }
+
+    fn instrument(&self, job: &CompileJob, profile_dir: &Path) -> BackendResult {
+        let mut cmd = self.rustc_command(job);
+        cmd.arg(format!(
+            "-Cprofile-generate={}",
+            profile_dir.to_string_lossy()
+        ));
+        cmd.args(["--cfg", INSTRUMENTED_CFG]);
+        run(cmd)
+    }
+
+    fn merge_profiles(&self, profile_dir: &Path, output: &Path) -> BackendResult {
+        let mut cmd = Command::new(&self.toolchain()?.llvm_profdata);
+        cmd.arg("merge");
+        cmd.arg("-o");
+        cmd.arg(output);
+        cmd.arg(profile_dir);
+        run(cmd)
+    }
+
+    fn optimize(&self, job: &CompileJob, profile: &Path) -> BackendResult {
+        let mut cmd = self.rustc_command(job);
+        cmd.arg(format!("-Cprofile-use={}", profile.to_string_lossy()));
+        run(cmd)
+    }
+
+    fn identity(&self) -> Option<Vec<u8>> {
+        let toolchain = self.toolchain().ok()?;
+        // A library built for another machine's CPU can't be loaded from a
+        // shared working directory
+        let native_cpu = self.native_cpu()?;
+        let link_fingerprint = self
+            .link_fingerprint
+            .get_or_init(|| self.link_info.fingerprint());
+        let identity = format!(
+            "{} {} {} {} {:?} {} {:?}",
+            toolchain.rustc_release,
+            toolchain.rustc_commit_hash,
+            toolchain.host,
+            native_cpu,
+            self.link_info,
+            link_fingerprint,
+            self.args
+        );
+        Some(identity.into_bytes())
+    }
+}
+
+/// Run a tool to completion, killing it if POGO is shut down in the meantime
+fn run(mut cmd: Command) -> BackendResult {
+    let command = format!("{:?}", cmd);
+    event!(debug, command = command; "running");
+
+    let spawn_error = |error| PogoError::Spawn {
+        command: command.clone(),
+        error,
+    };
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+
+    // Read stderr on the side, the child blocks once the pipe is full
+    let mut pipe = child.stderr.take().expect("stderr is piped");
+    let reader = std::thread::spawn(move || {
+        let mut stderr = Vec::new();
+        let _ = pipe.read_to_end(&mut stderr);
+        stderr
+    });
+
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(spawn_error)? {
+            break status;
+        }
+        if crate::is_shutting_down() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Box::new(PogoError::Cancelled { command }));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    let stderr = String::from_utf8_lossy(&reader.join().unwrap_or_default()).into_owned();
+    if !stderr.is_empty() {
+        event!(warn, command = command, stderr = stderr; "tool output");
+    }
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Box::new(PogoError::Exit {
+            command,
+            status,
+            stderr,
+        }))
+    }
+}
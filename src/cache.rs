@@ -0,0 +1,87 @@
+//! Content addressed cache of optimized libraries, so a restarted process can
+//! skip straight to the optimized version of a function it has seen before
+
+use crate::backend::CompilerBackend;
+use std::path::{Path, PathBuf};
+
+const ARTIFACT_NAME: &str = "optimized.so";
+
+#[derive(Debug)]
+pub struct ArtifactCache {
+    dir: PathBuf,
+    /// Identity of the compiler backend, part of every key since libraries
+    /// from another compiler version aren't interchangeable
+    compiler: Vec<u8>,
+}
+
+impl ArtifactCache {
+    /// Open the cache in `dir`, returns `None` if the backend can't identify
+    /// its compiler since nothing could be safely looked up then
+    pub fn new(dir: PathBuf, backend: &dyn CompilerBackend) -> Option<ArtifactCache> {
+        Some(ArtifactCache {
+            dir,
+            compiler: backend.identity()?,
+        })
+    }
+
+    /// Compute the cache key for a library built from `parts`, which should
+    /// cover the source and every flag that affects code generation
+    pub fn key(&self, parts: &[&[u8]]) -> String {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&self.compiler);
+        for part in parts {
+            // Length prefix so moving bytes between parts changes the key
+            hasher.write(&(part.len() as u64).to_le_bytes());
+            hasher.write(part);
+        }
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Path of the cached library for `key`, if there is one
+    pub fn lookup(&self, key: &str) -> Option<PathBuf> {
+        let path = self.dir.join(key).join(ARTIFACT_NAME);
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Copy a freshly built library into the cache
+    pub fn store(&self, key: &str, artifact: &Path) -> std::io::Result<()> {
+        let entry_dir = self.dir.join(key);
+        std::fs::create_dir_all(&entry_dir)?;
+
+        // Copy then rename so a crash never leaves a partial library behind
+        // for the next run to load
+        let tmp_path = entry_dir.join(format!("{}.tmp", ARTIFACT_NAME));
+        std::fs::copy(artifact, &tmp_path)?;
+        std::fs::rename(&tmp_path, entry_dir.join(ARTIFACT_NAME))
+    }
+
+    /// Drop an entry that failed to load
+    pub fn evict(&self, key: &str) {
+        let _ = std::fs::remove_dir_all(self.dir.join(key));
+    }
+}
+
+/// 64-bit FNV-1a, used because the keys have to be stable between runs and
+/// builds of the host, which `std`'s hashers don't promise
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
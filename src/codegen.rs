@@ -0,0 +1,183 @@
+//! Code generation settings for the run-time compiled libraries
+
+use crate::OptLevel;
+use std::process::Command;
+
+/// How the instrumented and optimized libraries of a group are compiled
+///
+/// The default, [`CodegenProfile::host`], optimizes like cargo's release
+/// profile while keeping the debug assertions and overflow checks of the host
+/// binary. Since the libraries only ever run on the machine that builds them
+/// it also targets the native CPU and uses a single codegen unit.
+///
+/// A group picks its profile with
+/// [`PogoGroup::CODEGEN`](crate::PogoGroup::CODEGEN), groups that don't use
+/// [`PogoConfig::codegen`](crate::PogoConfig::codegen). The `codegen` and
+/// `opt_level` arguments of `#[pogo]` override both for a single function.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct CodegenProfile {
+    pub opt_level: OptLevel,
+    /// `-Ctarget-cpu`, rustc's default for the target when `None`
+    pub target_cpu: Option<&'static str>,
+    /// `-Ccodegen-units`, rustc's default when `None`
+    pub codegen_units: Option<u32>,
+    /// Fat LTO across the library and the crates it links. The linked crates
+    /// need to contain bitcode, which cargo only embeds when the host crate's
+    /// profile enables LTO as well
+    pub lto: bool,
+    /// Has to match the host binary, a library can't use a different panic
+    /// runtime than the crates it links
+    pub panic: PanicStrategy,
+    pub debug_assertions: bool,
+    pub overflow_checks: bool,
+}
+
+impl CodegenProfile {
+    /// [`release`](Self::release) with debug assertions and overflow checks
+    /// on if the host binary has debug assertions
+    ///
+    /// Only debug assertions can be seen from the host build, overflow checks
+    /// are assumed to follow them like they do in cargo's profiles. A profile
+    /// setting `overflow-checks` on its own needs
+    /// [`overflow_checks`](Self::overflow_checks) set to match.
+    pub const fn host() -> CodegenProfile {
+        CodegenProfile::release()
+            .debug_assertions(cfg!(debug_assertions))
+            .overflow_checks(cfg!(debug_assertions))
+    }
+
+    /// `opt-level=3` without debug assertions or overflow checks, built for
+    /// the native CPU with a single codegen unit
+    pub const fn release() -> CodegenProfile {
+        CodegenProfile {
+            opt_level: OptLevel::O3,
+            target_cpu: Some("native"),
+            codegen_units: Some(1),
+            lto: false,
+            panic: PanicStrategy::host(),
+            debug_assertions: false,
+            overflow_checks: false,
+        }
+    }
+
+    /// `opt-level=0` with debug assertions and overflow checks, like cargo's
+    /// dev profile
+    pub const fn dev() -> CodegenProfile {
+        CodegenProfile {
+            opt_level: OptLevel::O0,
+            target_cpu: None,
+            codegen_units: None,
+            lto: false,
+            panic: PanicStrategy::host(),
+            debug_assertions: true,
+            overflow_checks: true,
+        }
+    }
+
+    pub const fn opt_level(self, opt_level: OptLevel) -> CodegenProfile {
+        CodegenProfile { opt_level, ..self }
+    }
+
+    /// `None` builds for the target's baseline CPU
+    pub const fn target_cpu(self, target_cpu: Option<&'static str>) -> CodegenProfile {
+        CodegenProfile { target_cpu, ..self }
+    }
+
+    pub const fn codegen_units(self, codegen_units: Option<u32>) -> CodegenProfile {
+        CodegenProfile {
+            codegen_units,
+            ..self
+        }
+    }
+
+    pub const fn lto(self, lto: bool) -> CodegenProfile {
+        CodegenProfile { lto, ..self }
+    }
+
+    pub const fn panic(self, panic: PanicStrategy) -> CodegenProfile {
+        CodegenProfile { panic, ..self }
+    }
+
+    pub const fn debug_assertions(self, debug_assertions: bool) -> CodegenProfile {
+        CodegenProfile {
+            debug_assertions,
+            ..self
+        }
+    }
+
+    pub const fn overflow_checks(self, overflow_checks: bool) -> CodegenProfile {
+        CodegenProfile {
+            overflow_checks,
+            ..self
+        }
+    }
+
+    /// Add the `-C` flags for this profile to a rustc invocation
+    pub fn add_rustc_args(&self, cmd: &mut Command) {
+        cmd.arg(format!("-Copt-level={}", self.opt_level.as_str()));
+        if let Some(target_cpu) = self.target_cpu {
+            cmd.arg(format!("-Ctarget-cpu={}", target_cpu));
+        }
+        if let Some(codegen_units) = self.codegen_units {
+            cmd.arg(format!("-Ccodegen-units={}", codegen_units));
+        }
+        if self.lto {
+            cmd.arg("-Clto=fat");
+        }
+        cmd.arg(format!("-Cpanic={}", self.panic.as_str()));
+        cmd.arg(format!(
+            "-Cdebug-assertions={}",
+            if self.debug_assertions { "on" } else { "off" }
+        ));
+        cmd.arg(format!(
+            "-Coverflow-checks={}",
+            if self.overflow_checks { "on" } else { "off" }
+        ));
+    }
+}
+
+impl Default for CodegenProfile {
+    fn default() -> Self {
+        CodegenProfile::host()
+    }
+}
+
+/// What a panic does in the run-time compiled library
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PanicStrategy {
+    Unwind,
+    Abort,
+}
+
+impl PanicStrategy {
+    /// The strategy the host binary was built with
+    pub const fn host() -> PanicStrategy {
+        if cfg!(panic = "abort") {
+            PanicStrategy::Abort
+        } else {
+            PanicStrategy::Unwind
+        }
+    }
+
+    /// The value passed to `rustc -Cpanic`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PanicStrategy::Unwind => "unwind",
+            PanicStrategy::Abort => "abort",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_follows_the_host_build() {
+        let profile = CodegenProfile::default();
+        assert_eq!(profile.debug_assertions, cfg!(debug_assertions));
+        assert_eq!(profile.overflow_checks, cfg!(debug_assertions));
+        assert_eq!(profile.panic, PanicStrategy::host());
+        assert_eq!(profile.opt_level, CodegenProfile::release().opt_level);
+    }
+}
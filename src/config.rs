@@ -0,0 +1,423 @@
+//! Settings passed to [`init`](crate::init)
+
+use crate::backend::{CompilerBackend, RustcBackend};
+use crate::CodegenProfile;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// How POGO compiles the run-time libraries and where it keeps them
+///
+/// Anything convertible to a path converts into a config using the default
+/// [`RustcBackend`](crate::backend::RustcBackend), so `init("./pogo", ...)`
+/// keeps working.
+///
+/// [`init`](crate::init) lets these environment variables override what the
+/// config sets, so a deployment can be retuned without rebuilding:
+///
+/// | Variable             | Setting                                              |
+/// |----------------------|------------------------------------------------------|
+/// | `POGO_ENABLED`       | [`enabled`](Self::enabled), `1`/`true`/`on` or `0`/`false`/`off` |
+/// | `POGO_WORKING_DIR`   | [`working_dir`](Self::working_dir)                   |
+/// | `POGO_RUSTC`         | [`rustc`](Self::rustc)                               |
+/// | `POGO_LLVM_PROFDATA` | [`llvm_profdata`](Self::llvm_profdata)               |
+/// | `POGO_RUSTC_FLAGS`   | [`rustc_args`](Self::rustc_args), split on whitespace |
+/// | `POGO_WORKERS`       | [`workers`](Self::workers)                           |
+/// | `POGO_THRESHOLD`     | [`default_threshold`](Self::default_threshold)       |
+/// | `POGO_RETENTION`     | [`retention`](Self::retention), `unload` or `keep-resident` |
+#[derive(Clone)]
+pub struct PogoConfig {
+    pub(crate) enabled: bool,
+    pub(crate) working_dir: PathBuf,
+    pub(crate) backend: Option<Arc<dyn CompilerBackend>>,
+    pub(crate) rustc: Option<PathBuf>,
+    pub(crate) llvm_profdata: Option<PathBuf>,
+    pub(crate) rustc_args: Vec<String>,
+    pub(crate) retention: LibraryRetention,
+    pub(crate) workers: usize,
+    pub(crate) default_threshold: Option<usize>,
+    pub(crate) codegen: CodegenProfile,
+}
+
+/// What happens to a run-time library once a group has moved on to a newer one
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum LibraryRetention {
+    /// Unload it as soon as no thread can still be running its code. Calls
+    /// hold off unloading while they run, a call that never returns keeps
+    /// every library replaced after it started loaded
+    #[default]
+    Unload,
+    /// Keep every library loaded until the process exits, for code that can't
+    /// be unloaded safely, like libraries registering thread-local destructors.
+    /// Calls don't have to hold off unloading
+    KeepResident,
+}
+
+impl PogoConfig {
+    pub fn new<P: Into<PathBuf>>(working_dir: P) -> Self {
+        PogoConfig {
+            enabled: true,
+            working_dir: working_dir.into(),
+            backend: None,
+            rustc: None,
+            llvm_profdata: None,
+            rustc_args: Vec::new(),
+            retention: LibraryRetention::default(),
+            workers: DEFAULT_WORKERS,
+            default_threshold: None,
+            codegen: CodegenProfile::host(),
+        }
+    }
+
+    /// Turn POGO off entirely, `init` then does nothing and every function
+    /// runs the version compiled with the project
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Where sources, libraries and profiles are written
+    pub fn working_dir<P: Into<PathBuf>>(mut self, working_dir: P) -> Self {
+        self.working_dir = working_dir.into();
+        self
+    }
+
+    /// Compile with `backend` instead of `rustc` and `llvm-profdata`, the tool
+    /// paths and rustc arguments set here only apply to the default backend
+    pub fn backend<B: CompilerBackend + 'static>(mut self, backend: B) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// The `rustc` the default backend runs
+    pub fn rustc<P: Into<PathBuf>>(mut self, rustc: P) -> Self {
+        self.rustc = Some(rustc.into());
+        self
+    }
+
+    /// The `llvm-profdata` the default backend runs instead of searching for
+    /// one
+    pub fn llvm_profdata<P: Into<PathBuf>>(mut self, llvm_profdata: P) -> Self {
+        self.llvm_profdata = Some(llvm_profdata.into());
+        self
+    }
+
+    /// Extra arguments for every `rustc` run by the default backend
+    pub fn rustc_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.rustc_args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Calls to profile before optimizing, for functions without a
+    /// `threshold` of their own. Replaces the
+    /// [`PGO_EXEC_COUNT`](crate::PogoGroup::PGO_EXEC_COUNT) of every group
+    pub fn default_threshold(mut self, threshold: usize) -> Self {
+        self.default_threshold = Some(threshold);
+        self
+    }
+
+    /// How libraries are compiled for groups without a
+    /// [`CODEGEN`](crate::PogoGroup::CODEGEN) of their own,
+    /// [`CodegenProfile::host`] by default. The default backend passes
+    /// [`rustc_args`](Self::rustc_args) after these flags so they can still
+    /// override single settings
+    pub fn codegen(mut self, codegen: CodegenProfile) -> Self {
+        self.codegen = codegen;
+        self
+    }
+
+    /// Whether replaced libraries get unloaded, [`LibraryRetention::Unload`]
+    /// by default
+    pub fn retention(mut self, retention: LibraryRetention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Number of worker threads, which is also the most compiles run at once.
+    /// One by default, so compiling never competes with the application for
+    /// more than a single core unless asked to
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+}
+
+impl std::fmt::Debug for PogoConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PogoConfig")
+            .field("enabled", &self.enabled)
+            .field("working_dir", &self.working_dir)
+            .field("custom_backend", &self.backend.is_some())
+            .field("rustc", &self.rustc)
+            .field("llvm_profdata", &self.llvm_profdata)
+            .field("rustc_args", &self.rustc_args)
+            .field("retention", &self.retention)
+            .field("workers", &self.workers)
+            .field("default_threshold", &self.default_threshold)
+            .field("codegen", &self.codegen)
+            .finish()
+    }
+}
+
+impl PogoConfig {
+    /// The default backend with the configured tools and arguments
+    pub(crate) fn rustc_backend(&self) -> RustcBackend {
+        let mut backend = RustcBackend::new().args(self.rustc_args.iter().cloned());
+        if let Some(rustc) = &self.rustc {
+            backend = backend.rustc(rustc);
+        }
+        if let Some(llvm_profdata) = &self.llvm_profdata {
+            backend = backend.llvm_profdata(llvm_profdata);
+        }
+        backend
+    }
+
+    /// Apply the `POGO_*` environment variables on top of this config
+    pub(crate) fn apply_env(mut self) -> Result<Self, ConfigError> {
+        if let Some(value) = env_var("POGO_ENABLED") {
+            self.enabled = match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "on" | "yes" => true,
+                "0" | "false" | "off" | "no" => false,
+                _ => return Err(ConfigError::new("POGO_ENABLED", value, "true or false")),
+            };
+        }
+        if let Some(value) = env_var("POGO_WORKING_DIR") {
+            self.working_dir = value.into();
+        }
+        if let Some(value) = env_var("POGO_RUSTC") {
+            self.rustc = Some(value.into());
+        }
+        if let Some(value) = env_var("POGO_LLVM_PROFDATA") {
+            self.llvm_profdata = Some(value.into());
+        }
+        if let Some(value) = env_var("POGO_RUSTC_FLAGS") {
+            self.rustc_args = value.split_whitespace().map(String::from).collect();
+        }
+        if let Some(value) = env_var("POGO_WORKERS") {
+            self.workers = match value.parse::<usize>() {
+                Ok(workers) if workers > 0 => workers,
+                _ => return Err(ConfigError::new("POGO_WORKERS", value, "a positive number")),
+            };
+        }
+        if let Some(value) = env_var("POGO_THRESHOLD") {
+            self.default_threshold = match value.parse() {
+                Ok(threshold) => Some(threshold),
+                Err(_) => return Err(ConfigError::new("POGO_THRESHOLD", value, "a number")),
+            };
+        }
+        if let Some(value) = env_var("POGO_RETENTION") {
+            self.retention = match value.as_str() {
+                "unload" => LibraryRetention::Unload,
+                "keep-resident" => LibraryRetention::KeepResident,
+                _ => {
+                    return Err(ConfigError::new(
+                        "POGO_RETENTION",
+                        value,
+                        "unload or keep-resident",
+                    ))
+                }
+            };
+        }
+        Ok(self)
+    }
+}
+
+/// A set but empty variable counts as unset
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+/// A `POGO_*` environment variable with a value that can't be used
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigError {
+    pub variable: &'static str,
+    pub value: String,
+    pub expected: &'static str,
+}
+
+impl ConfigError {
+    fn new(variable: &'static str, value: String, expected: &'static str) -> ConfigError {
+        ConfigError {
+            variable,
+            value,
+            expected,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}={:?} is invalid, expected {}",
+            self.variable, self.value, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+const DEFAULT_WORKERS: usize = 1;
+
+impl From<PathBuf> for PogoConfig {
+    fn from(working_dir: PathBuf) -> Self {
+        PogoConfig::new(working_dir)
+    }
+}
+
+impl From<&Path> for PogoConfig {
+    fn from(working_dir: &Path) -> Self {
+        PogoConfig::new(working_dir)
+    }
+}
+
+impl From<String> for PogoConfig {
+    fn from(working_dir: String) -> Self {
+        PogoConfig::new(working_dir)
+    }
+}
+
+impl From<&str> for PogoConfig {
+    fn from(working_dir: &str) -> Self {
+        PogoConfig::new(working_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const VARIABLES: &[&str] = &[
+        "POGO_ENABLED",
+        "POGO_WORKING_DIR",
+        "POGO_RUSTC",
+        "POGO_LLVM_PROFDATA",
+        "POGO_RUSTC_FLAGS",
+        "POGO_WORKERS",
+        "POGO_THRESHOLD",
+        "POGO_RETENTION",
+    ];
+
+    // The environment is shared by every test thread
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _lock = ENV_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for name in VARIABLES {
+            std::env::remove_var(name);
+        }
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let result = f();
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        result
+    }
+
+    fn apply_with(vars: &[(&str, &str)]) -> Result<PogoConfig, ConfigError> {
+        with_env(vars, || PogoConfig::new("./pogo").apply_env())
+    }
+
+    #[test]
+    fn unset_keeps_config() {
+        let config = apply_with(&[]).unwrap();
+        assert!(config.enabled);
+        assert_eq!(config.working_dir, PathBuf::from("./pogo"));
+        assert_eq!(config.workers, DEFAULT_WORKERS);
+        assert_eq!(config.default_threshold, None);
+        assert_eq!(config.retention, LibraryRetention::Unload);
+    }
+
+    #[test]
+    fn valid_values() {
+        let config = apply_with(&[
+            ("POGO_ENABLED", "Off"),
+            ("POGO_WORKING_DIR", "/tmp/pogo"),
+            ("POGO_RUSTC", "/opt/rustc"),
+            ("POGO_LLVM_PROFDATA", "/opt/llvm-profdata"),
+            ("POGO_RUSTC_FLAGS", " -Cdebuginfo=1  -Zfoo "),
+            ("POGO_WORKERS", "3"),
+            ("POGO_THRESHOLD", "500"),
+            ("POGO_RETENTION", "keep-resident"),
+        ])
+        .unwrap();
+        assert!(!config.enabled);
+        assert_eq!(config.working_dir, PathBuf::from("/tmp/pogo"));
+        assert_eq!(config.rustc, Some(PathBuf::from("/opt/rustc")));
+        assert_eq!(
+            config.llvm_profdata,
+            Some(PathBuf::from("/opt/llvm-profdata"))
+        );
+        assert_eq!(config.rustc_args, ["-Cdebuginfo=1", "-Zfoo"]);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.default_threshold, Some(500));
+        assert_eq!(config.retention, LibraryRetention::KeepResident);
+
+        for value in ["1", "true", "ON", "yes"] {
+            assert!(apply_with(&[("POGO_ENABLED", value)]).unwrap().enabled);
+        }
+    }
+
+    #[test]
+    fn empty_counts_as_unset() {
+        let config = apply_with(&[("POGO_WORKERS", " "), ("POGO_ENABLED", "")]).unwrap();
+        assert!(config.enabled);
+        assert_eq!(config.workers, DEFAULT_WORKERS);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let cases = [
+            ("POGO_ENABLED", "maybe"),
+            ("POGO_WORKERS", "0"),
+            ("POGO_WORKERS", "-1"),
+            ("POGO_WORKERS", "many"),
+            ("POGO_THRESHOLD", "1e3"),
+            ("POGO_RETENTION", "keep_resident"),
+        ];
+        for (name, value) in cases {
+            let error = apply_with(&[(name, value)]).unwrap_err();
+            assert_eq!(error.variable, name);
+            assert_eq!(error.value, value);
+        }
+    }
+
+    #[test]
+    fn check_toolchain_uses_the_config() {
+        use crate::{check_toolchain, ToolchainError};
+
+        let config = PogoConfig::new("./pogo").rustc("/nonexistent/configured-rustc");
+        match with_env(&[], || check_toolchain(&config)) {
+            Err(ToolchainError::RustcNotFound { rustc, .. }) => {
+                assert_eq!(rustc, PathBuf::from("/nonexistent/configured-rustc"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let vars = [("POGO_RUSTC", "/nonexistent/env-rustc")];
+        match with_env(&vars, || check_toolchain(&config)) {
+            Err(ToolchainError::RustcNotFound { rustc, .. }) => {
+                assert_eq!(rustc, PathBuf::from("/nonexistent/env-rustc"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let vars = [("POGO_WORKERS", "none")];
+        match with_env(&vars, || check_toolchain(&config)) {
+            Err(ToolchainError::InvalidConfig(error)) => {
+                assert_eq!(error.variable, "POGO_WORKERS")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
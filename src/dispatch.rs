@@ -0,0 +1,625 @@
+//! The per-function state calls go through, read without locks or hashing
+//!
+//! Every function has a fixed size table of optimization groups. A group's
+//! slot is found by probing from its [`PogoGroup::KEY`](crate::PogoGroup::KEY),
+//! a hash of its name computed at compile time, so the first probe almost
+//! always hits. Slots are claimed the first time a group is used and never
+//! released, which keeps the probing valid without any locking.
+
+use crate::status::{GroupStats, GroupStatus, StateKind};
+use crate::trace::event;
+use crate::{
+    submit_optimization_request, submit_verification, CodegenProfile, LibraryRetention, PgoState,
+    PogoError, PogoFuncCtx, PogoFuncDefinition,
+};
+use crossbeam::epoch;
+use libloading::Library;
+use once_cell::sync::OnceCell;
+use std::ffi::c_void;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// Libraries no call can be running in anymore, waiting for a worker to
+/// unload them
+static UNLOADED: Mutex<Vec<Library>> = Mutex::new(Vec::new());
+
+/// Libraries retired with [`LibraryRetention::Unload`] that haven't been
+/// unloaded yet
+static UNLOADING: AtomicUsize = AtomicUsize::new(0);
+
+/// Unload the retired libraries no call can be running in anymore, returns
+/// whether there are others still waiting for calls to return
+///
+/// Unloading runs the library's destructors, for an instrumented one that
+/// includes writing its profile, so this is left to the workers instead of
+/// whichever thread happens to collect the epoch's garbage.
+pub(crate) fn unload_retired() -> bool {
+    // Lets the epoch move on even if no call pins it
+    epoch::pin().flush();
+
+    let unloaded = std::mem::take(&mut *UNLOADED.lock().unwrap_or_else(PoisonError::into_inner));
+    let remaining = UNLOADING.fetch_sub(unloaded.len(), Ordering::AcqRel) - unloaded.len();
+    drop(unloaded);
+    remaining > 0
+}
+
+/// Number of optimization groups a single function can be called through,
+/// calls through any further groups run the natively compiled function
+pub const GROUP_SLOTS: usize = 16;
+
+/// Key of a group's slot, the 64-bit FNV-1a hash of its name. Never 0 since
+/// that marks an empty slot
+pub const fn group_key(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        hash ^= bytes[idx] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        idx += 1;
+    }
+
+    if hash == 0 {
+        1
+    } else {
+        hash
+    }
+}
+
+/// The optimization groups of one function
+pub struct GroupTable {
+    slots: [GroupSlot; GROUP_SLOTS],
+}
+
+struct GroupSlot {
+    key: AtomicU64,
+    /// Set right after the key is claimed, until then the group is treated as
+    /// missing
+    state: OnceCell<GroupState>,
+}
+
+impl GroupTable {
+    pub fn new() -> GroupTable {
+        GroupTable {
+            slots: std::array::from_fn(|_| GroupSlot {
+                key: AtomicU64::new(0),
+                state: OnceCell::new(),
+            }),
+        }
+    }
+
+    /// The group with the given key, if it has been created
+    #[inline]
+    pub fn get(&self, key: u64) -> Option<&GroupState> {
+        let start = key as usize % GROUP_SLOTS;
+        for offset in 0..GROUP_SLOTS {
+            let slot = &self.slots[(start + offset) % GROUP_SLOTS];
+            match slot.key.load(Ordering::Acquire) {
+                k if k == key => return slot.state.get(),
+                0 => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Create a group, returns it only if this call created it so exactly one
+    /// caller goes on to compile it. `None` if it already exists or the table
+    /// is full
+    pub fn insert(
+        &self,
+        key: u64,
+        name: &'static str,
+        threshold: usize,
+        codegen: CodegenProfile,
+        info: &PogoFuncDefinition,
+    ) -> Option<&GroupState> {
+        let start = key as usize % GROUP_SLOTS;
+        for offset in 0..GROUP_SLOTS {
+            let slot = &self.slots[(start + offset) % GROUP_SLOTS];
+            match slot
+                .key
+                .compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    let _ = slot
+                        .state
+                        .set(GroupState::new(name, threshold, codegen, info));
+                    return slot.state.get();
+                }
+                Err(existing) if existing == key => return None,
+                Err(_) => {}
+            }
+        }
+        None
+    }
+
+    /// Look a group up by name, for the worker which only has the name
+    pub fn find(&self, name: &str) -> Option<&GroupState> {
+        self.get(group_key(name))
+    }
+
+    /// Every group created so far
+    pub fn iter(&self) -> impl Iterator<Item = &GroupState> {
+        self.slots.iter().filter_map(|slot| slot.state.get())
+    }
+}
+
+impl Default for GroupTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for GroupTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|group| (group.name, group)))
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct GroupState {
+    pub name: &'static str,
+    /// Calls to profile before building the optimized library
+    pub threshold: usize,
+    /// How the group's libraries are compiled
+    pub codegen: CodegenProfile,
+    /// Changed by the worker, calls never lock this and use `funcs` instead
+    pgo_state: Mutex<PgoState>,
+    pub pgo_count: AtomicUsize,
+    /// The exported functions of the library in `pgo_state`
+    pub funcs: FuncTable,
+    /// Set while the library in `pgo_state` is gathering profiling data
+    profiling: AtomicBool,
+    /// Set while calls compare the library in `pgo_state` with the natively
+    /// compiled function
+    verifying: AtomicBool,
+    /// Calls that matched since verifying started
+    verified_count: AtomicUsize,
+    /// Replaced libraries kept loaded by [`LibraryRetention::KeepResident`]
+    retired: Mutex<Vec<Library>>,
+    stats: Mutex<GroupStats>,
+}
+
+impl GroupState {
+    fn new(
+        name: &'static str,
+        threshold: usize,
+        codegen: CodegenProfile,
+        info: &PogoFuncDefinition,
+    ) -> GroupState {
+        let mut stats = GroupStats::default();
+        stats
+            .entered
+            .entered(StateKind::Uninitialized, SystemTime::now());
+
+        GroupState {
+            name,
+            threshold,
+            codegen,
+            pgo_state: Mutex::new(PgoState::Uninitialized),
+            pgo_count: AtomicUsize::new(0),
+            funcs: FuncTable::new(info.symbols.len()),
+            profiling: AtomicBool::new(false),
+            verifying: AtomicBool::new(false),
+            verified_count: AtomicUsize::new(0),
+            retired: Mutex::new(Vec::new()),
+            stats: Mutex::new(stats),
+        }
+    }
+
+    /// The current state, calls are not blocked while this is held
+    pub fn state(&self) -> MutexGuard<'_, PgoState> {
+        self.pgo_state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn stats(&self) -> MutexGuard<'_, GroupStats> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A snapshot of the group for [`status`](crate::status)
+    pub fn status(&self) -> GroupStatus {
+        let state = self.state();
+        let stats = self.stats();
+        GroupStatus {
+            name: self.name.to_string(),
+            state: StateKind::from(&*state),
+            call_count: self.pgo_count.load(Ordering::Relaxed),
+            threshold: self.threshold,
+            entered: stats.entered.clone(),
+            instrument_duration: stats.instrument_duration,
+            optimize_duration: stats.optimize_duration,
+            instrumented_library: stats.instrumented_library.clone(),
+            optimized_library: stats.optimized_library.clone(),
+            verified_count: self.verified_count.load(Ordering::Relaxed),
+            last_error: match &*state {
+                PgoState::CompilationFailed(error) => Some(error.to_string()),
+                PgoState::Rejected(report) => Some(report.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Count a call while profiling, requesting the optimized library once
+    /// `threshold` calls have been made
+    ///
+    /// Crossing the threshold ends the profiling phase, only the call that
+    /// ends it submits the request.
+    #[inline]
+    pub fn record_execution(&'static self, ctx: &'static PogoFuncCtx) {
+        if self.profiling.load(Ordering::Relaxed)
+            && self.pgo_count.fetch_add(1, Ordering::Relaxed) >= self.threshold
+            && self
+                .profiling
+                .compare_exchange(true, false, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            submit_optimization_request(ctx, self.name);
+        }
+    }
+
+    /// Whether calls have to run the natively compiled function alongside the
+    /// library and hand both results to
+    /// [`record_verification`](Self::record_verification)
+    #[inline]
+    pub fn is_verifying(&self) -> bool {
+        self.verifying.load(Ordering::Relaxed)
+    }
+
+    /// Compare what the library returned for a call with what the natively
+    /// compiled function returned
+    ///
+    /// A mismatch stops calls from using the library right away and has the
+    /// worker reject it. Once the function's `verify` calls matched the call
+    /// that got there has the worker switch the group over to
+    /// [`PgoState::Optimized`].
+    pub fn record_verification<T: PartialEq + Debug>(
+        &'static self,
+        ctx: &'static PogoFuncCtx,
+        native: &T,
+        library: &T,
+    ) {
+        if native != library {
+            // Several calls can disagree at once, only the first one rejects
+            // the library
+            if self
+                .verifying
+                .compare_exchange(true, false, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+            {
+                return;
+            }
+            self.funcs.clear();
+            let report = format!(
+                "call {} returned a different result than the natively compiled function\n  native:  {:?}\n  library: {:?}",
+                self.verified_count.load(Ordering::Relaxed) + 1,
+                native,
+                library
+            );
+            submit_verification(ctx, self.name, Some(report));
+        } else if self.verified_count.fetch_add(1, Ordering::Relaxed) + 1 >= ctx.info.verify
+            && self
+                .verifying
+                .compare_exchange(true, false, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            submit_verification(ctx, self.name, None);
+        }
+    }
+
+    /// Replace the state, the exported functions of a newly loaded library are
+    /// resolved here and a library missing any of them fails the group
+    pub(crate) fn set_state(
+        &self,
+        info: &PogoFuncDefinition,
+        state: PgoState,
+        retention: LibraryRetention,
+    ) {
+        let mut current = self.state();
+
+        let state = match &state {
+            PgoState::GatheringData(lib)
+            | PgoState::Compiling(lib)
+            | PgoState::Verifying(lib)
+            | PgoState::Optimized(lib) => match self.funcs.resolve(lib, info.symbols) {
+                Ok(()) => state,
+                Err(error) => PgoState::failed(error),
+            },
+            PgoState::Uninitialized | PgoState::CompilationFailed(_) | PgoState::Rejected(_) => {
+                state
+            }
+        };
+        if let PgoState::Uninitialized | PgoState::CompilationFailed(_) | PgoState::Rejected(_) =
+            state
+        {
+            self.funcs.clear();
+        }
+        match &state {
+            PgoState::GatheringData(_) => event!(
+                info,
+                function = info.name,
+                group = self.name;
+                "gathering profiling data"
+            ),
+            PgoState::Verifying(_) => event!(
+                info,
+                function = info.name,
+                group = self.name,
+                calls = info.verify;
+                "verifying optimized library against the natively compiled function"
+            ),
+            PgoState::Optimized(_) => event!(
+                info,
+                function = info.name,
+                group = self.name;
+                "running optimized library"
+            ),
+            PgoState::Rejected(report) => event!(
+                error,
+                function = info.name,
+                group = self.name,
+                report = report;
+                "optimized library rejected, running the natively compiled function"
+            ),
+            PgoState::CompilationFailed(error) => event!(
+                error,
+                function = info.name,
+                group = self.name,
+                error = error.to_string();
+                "compilation failed, running the natively compiled function"
+            ),
+            PgoState::Uninitialized | PgoState::Compiling(_) => {}
+        }
+
+        let profiling = matches!(state, PgoState::GatheringData(_));
+        if profiling {
+            self.pgo_count.store(0, Ordering::Relaxed);
+        }
+        self.profiling.store(profiling, Ordering::Release);
+        let verifying = matches!(state, PgoState::Verifying(_));
+        if verifying {
+            self.verified_count.store(0, Ordering::Relaxed);
+        }
+        self.verifying.store(verifying, Ordering::Release);
+        self.stats()
+            .entered
+            .entered(StateKind::from(&state), SystemTime::now());
+
+        if let PgoState::GatheringData(lib)
+        | PgoState::Compiling(lib)
+        | PgoState::Verifying(lib)
+        | PgoState::Optimized(lib) = std::mem::replace(&mut *current, state)
+        {
+            self.retire(lib, retention);
+        }
+    }
+
+    /// Apply the outcome of verifying the optimized library, running it on
+    /// its own or rejecting it with the `mismatch` report
+    pub(crate) fn finish_verifying(
+        &self,
+        info: &PogoFuncDefinition,
+        mismatch: Option<String>,
+        retention: LibraryRetention,
+    ) {
+        match mismatch {
+            Some(report) => {
+                if matches!(*self.state(), PgoState::Verifying(_)) {
+                    self.set_state(info, PgoState::Rejected(report), retention);
+                }
+            }
+            None => {
+                let mut state = self.state();
+                // The library stays where `funcs` already points
+                match std::mem::replace(&mut *state, PgoState::Uninitialized) {
+                    PgoState::Verifying(lib) => {
+                        *state = PgoState::Optimized(lib);
+                        self.stats()
+                            .entered
+                            .entered(StateKind::Optimized, SystemTime::now());
+                        event!(
+                            info,
+                            function = info.name,
+                            group = self.name;
+                            "running optimized library"
+                        );
+                    }
+                    // The group moved on while the outcome was queued
+                    other => *state = other,
+                }
+            }
+        }
+    }
+
+    /// Dispose of a library `funcs` no longer points into
+    fn retire(&self, lib: Library, retention: LibraryRetention) {
+        match retention {
+            LibraryRetention::Unload => {
+                // Calls stay pinned from loading an address out of `funcs`
+                // until they return, so once every thread pinned right now has
+                // unpinned nothing can be running in `lib` anymore. That can
+                // be noticed on a calling thread, which only hands the library
+                // over to the workers
+                UNLOADING.fetch_add(1, Ordering::AcqRel);
+                let guard = epoch::pin();
+                guard.defer(move || {
+                    UNLOADED
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(lib)
+                });
+                guard.flush();
+            }
+            LibraryRetention::KeepResident => self
+                .retired
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(lib),
+        }
+    }
+
+    /// Write the counters of the library being compiled from to its profile
+    /// directory and zero them, so what the profiling runtime writes when the
+    /// library is unloaded only covers calls made after this
+    ///
+    /// Returns `false` if the runtime failed to write the profile. A library
+    /// that doesn't export the functions for this, from a backend not setting
+    /// [`INSTRUMENTED_CFG`](crate::backend::INSTRUMENTED_CFG), is left alone.
+    pub(crate) fn write_profile(&self) -> bool {
+        let state = self.state();
+        let lib = match &*state {
+            PgoState::Compiling(lib) => lib,
+            _ => return false,
+        };
+
+        unsafe {
+            let write: libloading::Symbol<unsafe extern "C" fn() -> i32> =
+                match lib.get(b"__pogo_profile_write_file") {
+                    Ok(write) => write,
+                    Err(_) => return true,
+                };
+            if write() != 0 {
+                return false;
+            }
+
+            if let Ok(reset) = lib.get::<unsafe extern "C" fn()>(b"__pogo_profile_reset_counters") {
+                reset();
+            }
+        }
+        true
+    }
+
+    /// Calls per second since profiling started, 0 if it never did
+    pub(crate) fn call_rate(&self) -> f64 {
+        let started = match self.stats().entered.gathering_data {
+            Some(started) => started,
+            None => return 0.0,
+        };
+        match started.elapsed() {
+            Ok(elapsed) if !elapsed.is_zero() => {
+                self.pgo_count.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Note the instrumented library the worker built
+    pub(crate) fn record_instrumented(&self, path: PathBuf, duration: Duration) {
+        let mut stats = self.stats();
+        stats.instrumented_library = Some(path);
+        stats.instrument_duration = Some(duration);
+    }
+
+    /// Note the optimized library the worker built, or loaded from the cache
+    /// without a `duration`
+    pub(crate) fn record_optimized(&self, path: PathBuf, duration: Option<Duration>) {
+        let mut stats = self.stats();
+        stats.optimized_library = Some(path);
+        stats.optimize_duration = duration;
+    }
+
+    /// Fail the group if its compile was cut short, leaving it alone if it
+    /// got to run a library or already failed
+    pub(crate) fn fail_unfinished(
+        &self,
+        info: &PogoFuncDefinition,
+        error: PogoError,
+        retention: LibraryRetention,
+    ) {
+        let unfinished = matches!(
+            *self.state(),
+            PgoState::Uninitialized | PgoState::Compiling(_)
+        );
+        if unfinished {
+            self.set_state(info, PgoState::failed(error), retention);
+        }
+    }
+
+    /// Why the group failed to compile, if it did
+    pub fn error(&self) -> Option<Arc<PogoError>> {
+        match &*self.state() {
+            PgoState::CompilationFailed(error) => Some(error.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether the group still needs its first library, anything else means
+    /// the initial request is a duplicate
+    pub(crate) fn is_uninitialized(&self) -> bool {
+        matches!(*self.state(), PgoState::Uninitialized)
+    }
+
+    /// Move from profiling to the optimized recompile, keeping the profiling
+    /// library running in the meantime
+    ///
+    /// Returns `false` for a request that doesn't belong to the current
+    /// profiling phase, a duplicate or one that arrived after the group moved
+    /// on, which the worker drops.
+    pub(crate) fn begin_compiling(&self) -> bool {
+        let mut state = self.state();
+        match std::mem::replace(&mut *state, PgoState::Uninitialized) {
+            PgoState::GatheringData(lib) => {
+                self.profiling.store(false, Ordering::Release);
+                *state = PgoState::Compiling(lib);
+                self.stats()
+                    .entered
+                    .entered(StateKind::Compiling, SystemTime::now());
+                true
+            }
+            other => {
+                *state = other;
+                false
+            }
+        }
+    }
+}
+
+/// Addresses of the functions a run-time library exports, in the order of
+/// [`PogoFuncDefinition::symbols`]
+///
+/// Calls load the address and jump straight to it instead of looking the
+/// symbol up in the library every time.
+#[derive(Debug)]
+pub struct FuncTable(Box<[AtomicPtr<c_void>]>);
+
+impl FuncTable {
+    fn new(len: usize) -> FuncTable {
+        FuncTable((0..len).map(|_| AtomicPtr::new(ptr::null_mut())).collect())
+    }
+
+    /// Address of the `idx`th symbol, null while no library is loaded
+    #[inline]
+    pub fn get(&self, idx: usize) -> *mut c_void {
+        self.0[idx].load(Ordering::Acquire)
+    }
+
+    fn resolve(&self, lib: &Library, symbols: &[&'static str]) -> Result<(), PogoError> {
+        // Look everything up first so a missing symbol leaves the table as it was
+        let mut addrs = Vec::with_capacity(symbols.len());
+        for &symbol in symbols {
+            let func: libloading::Symbol<unsafe extern "C" fn()> =
+                unsafe { lib.get(symbol.as_bytes()) }
+                    .map_err(|error| PogoError::MissingSymbol { symbol, error })?;
+            addrs.push(*func as *mut c_void);
+        }
+
+        for (slot, addr) in self.0.iter().zip(addrs) {
+            slot.store(addr, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn clear(&self) {
+        for slot in self.0.iter() {
+            slot.store(ptr::null_mut(), Ordering::Release);
+        }
+    }
+}
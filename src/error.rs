@@ -0,0 +1,131 @@
+//! Why a group failed to compile
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+/// Why an optimization group ended up in
+/// [`PgoState::CompilationFailed`](crate::PgoState::CompilationFailed)
+#[derive(Debug)]
+pub enum PogoError {
+    /// The group's working directory couldn't be created
+    CreateDir {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// A tool couldn't be run
+    Spawn {
+        command: String,
+        error: std::io::Error,
+    },
+    /// A tool ran but failed, with everything it wrote to stderr
+    Exit {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// A tool was killed by [`shutdown`](crate::shutdown)
+    Cancelled { command: String },
+    /// The profile of the instrumented library couldn't be gathered
+    Merge {
+        profile_dir: PathBuf,
+        reason: String,
+    },
+    /// A compiled library couldn't be loaded
+    Load {
+        path: PathBuf,
+        error: libloading::Error,
+    },
+    /// A loaded library doesn't export one of the functions it was built for
+    MissingSymbol {
+        symbol: &'static str,
+        error: libloading::Error,
+    },
+    /// The worker panicked while compiling the group
+    WorkerPanicked { message: String },
+    /// Any other error returned by a custom
+    /// [`CompilerBackend`](crate::backend::CompilerBackend)
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+impl PogoError {
+    /// Recover the error a backend returned, the default backend already
+    /// returns `PogoError`s
+    pub(crate) fn from_backend(error: Box<dyn Error + Send + Sync>) -> PogoError {
+        match error.downcast::<PogoError>() {
+            Ok(error) => *error,
+            Err(error) => PogoError::Backend(error),
+        }
+    }
+}
+
+impl fmt::Display for PogoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PogoError::CreateDir { path, error } => {
+                write!(f, "could not create {}: {}", path.display(), error)
+            }
+            PogoError::Spawn { command, error } => {
+                write!(f, "could not run {}: {}", command, error)
+            }
+            PogoError::Exit {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "{} failed with {}", command, status)?;
+                if !stderr.is_empty() {
+                    write!(f, ":\n{}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            PogoError::Cancelled { command } => {
+                write!(f, "{} was killed by shutting down", command)
+            }
+            PogoError::Merge {
+                profile_dir,
+                reason,
+            } => write!(
+                f,
+                "could not gather the profile in {}: {}",
+                profile_dir.display(),
+                reason
+            ),
+            PogoError::Load { path, error } => {
+                write!(f, "could not load {}: {}", path.display(), error)
+            }
+            PogoError::MissingSymbol { symbol, error } => {
+                write!(f, "library doesn't export {}: {}", symbol, error)
+            }
+            PogoError::WorkerPanicked { message } => {
+                write!(f, "the worker panicked: {}", message)
+            }
+            PogoError::Backend(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for PogoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PogoError::CreateDir { error, .. } | PogoError::Spawn { error, .. } => Some(error),
+            PogoError::Load { error, .. } | PogoError::MissingSymbol { error, .. } => Some(error),
+            PogoError::Backend(error) => error.source(),
+            PogoError::Exit { .. }
+            | PogoError::Cancelled { .. }
+            | PogoError::Merge { .. }
+            | PogoError::WorkerPanicked { .. } => None,
+        }
+    }
+}
+
+/// A group that failed to compile, see [`errors`](crate::errors)
+#[derive(Clone, Debug)]
+pub struct GroupError {
+    /// Name of the `#[pogo]` function, `Type::method` for methods
+    pub function: &'static str,
+    pub group: &'static str,
+    pub error: Arc<PogoError>,
+}
@@ -0,0 +1,97 @@
+//! Types std types are lowered to when crossing into the run-time compiled
+//! library, used by the code `#[pogo]` generates
+//!
+//! Borrowed slices and strings are passed as a pointer and length. Owned
+//! `String`s and `Vec`s move to the callee (or back to the caller when
+//! returned): their elements are moved into a buffer allocated by the receiving
+//! side and the original buffer is freed by the side that allocated it, so the
+//! host and the library never free each other's memory.
+
+include!("ffi_types.rs");
+
+/// Source of this module, compiled into every run-time library
+pub(crate) const SRC: &str = include_str!("ffi_types.rs");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vec_round_trip() {
+        let vec = vec!["a".to_string(), "bc".to_string(), String::new()];
+        let back = unsafe { FfiVec::new(vec.clone()).into_vec() };
+        assert_eq!(back, vec);
+    }
+
+    #[test]
+    fn empty_vec_round_trip() {
+        let back = unsafe { FfiVec::new(Vec::<u64>::new()).into_vec() };
+        assert!(back.is_empty());
+
+        let back = unsafe { FfiVec::new(Vec::<u64>::with_capacity(16)).into_vec() };
+        assert!(back.is_empty());
+    }
+
+    #[test]
+    fn vec_of_zero_sized_round_trip() {
+        let back = unsafe { FfiVec::new(vec![(); 3]).into_vec() };
+        assert_eq!(back.len(), 3);
+    }
+
+    #[test]
+    fn string_round_trip() {
+        for s in ["", "pogo", "grüße ✓"] {
+            let back = unsafe { FfiString::new(s.to_string()).into_string() };
+            assert_eq!(back, s);
+        }
+    }
+
+    #[test]
+    fn borrowed_round_trip() {
+        assert_eq!(unsafe { FfiStr::new("pogo").as_str() }, "pogo");
+        assert_eq!(
+            unsafe { FfiSlice::new(&[1u8, 2, 3]).as_slice() },
+            &[1, 2, 3]
+        );
+
+        let mut values = [1u32, 2];
+        unsafe { FfiSliceMut::new(&mut values).as_mut_slice()[1] = 5 };
+        assert_eq!(values, [1, 5]);
+    }
+
+    #[test]
+    fn option_round_trip() {
+        assert_eq!(FfiOption::new(Some(7u32)).into_option(), Some(7));
+        assert_eq!(FfiOption::<u32>::new(None).into_option(), None);
+
+        let some = FfiOption::new(Some(FfiString::new("x".to_string())));
+        let back = some.into_option().map(|s| unsafe { s.into_string() });
+        assert_eq!(back.as_deref(), Some("x"));
+        assert!(FfiOption::<FfiString>::new(None).into_option().is_none());
+    }
+
+    #[test]
+    fn result_round_trip() {
+        assert_eq!(FfiResult::<u32, i8>::new(Ok(7)).into_result(), Ok(7));
+        assert_eq!(FfiResult::<u32, i8>::new(Err(-1)).into_result(), Err(-1));
+
+        let err = FfiResult::<u32, FfiString>::new(Err(FfiString::new("bad".to_string())));
+        let back = err.into_result().map_err(|e| unsafe { e.into_string() });
+        assert_eq!(back, Err("bad".to_string()));
+    }
+
+    #[test]
+    fn caught_panic_is_resumed() {
+        let mut panic = FfiPanic::new();
+        let value = unsafe { catch_panic(&mut panic, || 7u32) };
+        assert_eq!(unsafe { panic.resume(value) }, 7);
+
+        let resumed = std::panic::catch_unwind(|| {
+            let mut panic = FfiPanic::new();
+            let value = unsafe { catch_panic(&mut panic, || -> u32 { panic!("out of {}", 3) }) };
+            unsafe { panic.resume(value) }
+        });
+        let payload = resumed.unwrap_err();
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "out of 3");
+    }
+}
@@ -0,0 +1,258 @@
+// The `#[repr(C)]` types `#[pogo]` lowers std types to when calling into the
+// run-time compiled library. This file is also copied into every library so
+// both sides agree on the layouts, it has to compile in any edition and can't
+// use anything outside of std.
+
+/// A `&str` as a pointer and length
+#[repr(C)]
+pub struct FfiStr {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl FfiStr {
+    pub fn new(s: &str) -> FfiStr {
+        FfiStr {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+
+    /// # Safety
+    /// The `str` this was created from has to outlive `'a`
+    pub unsafe fn as_str<'a>(self) -> &'a str {
+        ::std::str::from_utf8_unchecked(::std::slice::from_raw_parts(self.ptr, self.len))
+    }
+}
+
+/// A `&[T]` as a pointer and length
+#[repr(C)]
+pub struct FfiSlice<T> {
+    ptr: *const T,
+    len: usize,
+}
+
+impl<T> FfiSlice<T> {
+    pub fn new(slice: &[T]) -> FfiSlice<T> {
+        FfiSlice {
+            ptr: slice.as_ptr(),
+            len: slice.len(),
+        }
+    }
+
+    /// # Safety
+    /// The slice this was created from has to outlive `'a`
+    pub unsafe fn as_slice<'a>(self) -> &'a [T] {
+        ::std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// A `&mut [T]` as a pointer and length
+#[repr(C)]
+pub struct FfiSliceMut<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T> FfiSliceMut<T> {
+    pub fn new(slice: &mut [T]) -> FfiSliceMut<T> {
+        FfiSliceMut {
+            ptr: slice.as_mut_ptr(),
+            len: slice.len(),
+        }
+    }
+
+    /// # Safety
+    /// The slice this was created from has to outlive `'a`
+    pub unsafe fn as_mut_slice<'a>(self) -> &'a mut [T] {
+        ::std::slice::from_raw_parts_mut(self.ptr, self.len)
+    }
+}
+
+/// An owned `Vec<T>` moving to the other side of the call
+///
+/// The host and the library may not share an allocator, so the receiver moves
+/// the elements into a buffer of its own and hands the original back to the
+/// side that allocated it through `release`.
+#[repr(C)]
+pub struct FfiVec<T> {
+    ptr: *mut T,
+    len: usize,
+    cap: usize,
+    release: unsafe extern "C" fn(*mut T, usize),
+}
+
+impl<T> FfiVec<T> {
+    pub fn new(vec: Vec<T>) -> FfiVec<T> {
+        let mut vec = ::std::mem::ManuallyDrop::new(vec);
+        FfiVec {
+            ptr: vec.as_mut_ptr(),
+            len: vec.len(),
+            cap: vec.capacity(),
+            release: release_vec::<T>,
+        }
+    }
+
+    /// # Safety
+    /// Has to be called exactly once, on the other side of the call from where
+    /// this was created
+    pub unsafe fn into_vec(self) -> Vec<T> {
+        let mut vec = Vec::with_capacity(self.len);
+        ::std::ptr::copy_nonoverlapping(self.ptr, vec.as_mut_ptr(), self.len);
+        vec.set_len(self.len);
+        (self.release)(self.ptr, self.cap);
+        vec
+    }
+}
+
+/// Free a buffer given away by `FfiVec::new`, its elements have been moved out
+unsafe extern "C" fn release_vec<T>(ptr: *mut T, cap: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, cap));
+}
+
+/// An owned `String`, see `FfiVec` for how ownership is passed
+#[repr(transparent)]
+pub struct FfiString(FfiVec<u8>);
+
+impl FfiString {
+    pub fn new(s: String) -> FfiString {
+        FfiString(FfiVec::new(s.into_bytes()))
+    }
+
+    /// # Safety
+    /// Same as `FfiVec::into_vec`
+    pub unsafe fn into_string(self) -> String {
+        String::from_utf8_unchecked(self.0.into_vec())
+    }
+}
+
+/// An `Option<T>` with an explicit tag
+#[repr(C)]
+pub struct FfiOption<T> {
+    is_some: bool,
+    value: ::std::mem::MaybeUninit<T>,
+}
+
+impl<T> FfiOption<T> {
+    pub fn new(option: Option<T>) -> FfiOption<T> {
+        match option {
+            Some(value) => FfiOption {
+                is_some: true,
+                value: ::std::mem::MaybeUninit::new(value),
+            },
+            None => FfiOption {
+                is_some: false,
+                value: ::std::mem::MaybeUninit::uninit(),
+            },
+        }
+    }
+
+    pub fn into_option(self) -> Option<T> {
+        if self.is_some {
+            // Only ever set alongside the value in `new`
+            Some(unsafe { self.value.assume_init() })
+        } else {
+            None
+        }
+    }
+}
+
+/// A `Result<T, E>` with an explicit tag
+#[repr(C)]
+pub struct FfiResult<T, E> {
+    is_ok: bool,
+    ok: ::std::mem::MaybeUninit<T>,
+    err: ::std::mem::MaybeUninit<E>,
+}
+
+impl<T, E> FfiResult<T, E> {
+    pub fn new(result: Result<T, E>) -> FfiResult<T, E> {
+        match result {
+            Ok(value) => FfiResult {
+                is_ok: true,
+                ok: ::std::mem::MaybeUninit::new(value),
+                err: ::std::mem::MaybeUninit::uninit(),
+            },
+            Err(err) => FfiResult {
+                is_ok: false,
+                ok: ::std::mem::MaybeUninit::uninit(),
+                err: ::std::mem::MaybeUninit::new(err),
+            },
+        }
+    }
+
+    pub fn into_result(self) -> Result<T, E> {
+        // The tag always matches the initialized field, see `new`
+        if self.is_ok {
+            Ok(unsafe { self.ok.assume_init() })
+        } else {
+            Err(unsafe { self.err.assume_init() })
+        }
+    }
+}
+
+/// A panic caught inside the library, the library links its own copy of std
+/// so the host can't catch a panic unwinding out of it. Only the message
+/// crosses over and the panic is resumed on the host
+#[repr(C)]
+pub struct FfiPanic {
+    panicked: bool,
+    message: ::std::mem::MaybeUninit<FfiString>,
+}
+
+impl FfiPanic {
+    pub fn new() -> FfiPanic {
+        FfiPanic {
+            panicked: false,
+            message: ::std::mem::MaybeUninit::uninit(),
+        }
+    }
+
+    /// Return the result of the call, or resume the panic that ended it
+    ///
+    /// # Safety
+    /// `value` has to come from `catch_panic` writing to this
+    pub unsafe fn resume<T>(self, value: ::std::mem::MaybeUninit<T>) -> T {
+        if self.panicked {
+            let message = self.message.assume_init().into_string();
+            ::std::panic::resume_unwind(Box::new(message));
+        }
+        value.assume_init()
+    }
+}
+
+impl Default for FfiPanic {
+    fn default() -> FfiPanic {
+        FfiPanic::new()
+    }
+}
+
+/// Run `f`, a panic leaves the result uninitialized and is written to `panic`
+///
+/// # Safety
+/// `panic` has to point to an `FfiPanic` that didn't catch a panic yet
+pub unsafe fn catch_panic<T, F: FnOnce() -> T>(
+    panic: *mut FfiPanic,
+    f: F,
+) -> ::std::mem::MaybeUninit<T> {
+    match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(f)) {
+        Ok(value) => ::std::mem::MaybeUninit::new(value),
+        Err(payload) => {
+            let message = match payload.downcast_ref::<&'static str>() {
+                Some(message) => message.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "Box<dyn Any>".to_string(),
+                },
+            };
+            ::std::ptr::write(
+                panic,
+                FfiPanic {
+                    panicked: true,
+                    message: ::std::mem::MaybeUninit::new(FfiString::new(message)),
+                },
+            );
+            ::std::mem::MaybeUninit::uninit()
+        }
+    }
+}
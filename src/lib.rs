@@ -1,15 +1,48 @@
 extern crate pogo_attr;
 
-use chashmap::CHashMap;
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use backend::{CompileJob, CompilerBackend};
+use cache::ArtifactCache;
+use dispatch::unload_retired;
 use libloading::Library;
+use linkme::distributed_slice;
 use once_cell::sync::OnceCell;
+use queue::{Pop, RequestQueue};
 use std::error::Error;
 use std::io::Write;
-use std::path::PathBuf;
-use std::sync::atomic::AtomicUsize;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use trace::{event, phase_span};
 
-pub use pogo_attr::pogo;
+pub use pogo_attr::{include, pogo};
+
+pub mod backend;
+mod cache;
+mod codegen;
+mod config;
+pub mod dispatch;
+mod error;
+pub mod ffi;
+pub mod link;
+mod queue;
+pub mod status;
+mod toolchain;
+mod trace;
+
+pub use codegen::{CodegenProfile, PanicStrategy};
+pub use config::{ConfigError, LibraryRetention, PogoConfig};
+pub use dispatch::{FuncTable, GroupState, GroupTable};
+pub use error::{GroupError, PogoError};
+pub use link::LinkInfo;
+pub use toolchain::{check_toolchain, ToolchainError, ToolchainReport};
+
+#[doc(hidden)]
+pub use crossbeam::epoch;
+#[doc(hidden)]
+pub use linkme;
 
 pub type ContextCell = once_cell::sync::OnceCell<PogoFuncCtx>;
 
@@ -17,25 +50,204 @@ pub type ContextCell = once_cell::sync::OnceCell<PogoFuncCtx>;
 pub enum Edition {
     Rust2015,
     Rust2018,
+    Rust2021,
+}
+
+impl Edition {
+    /// The value passed to `rustc --edition`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Edition::Rust2015 => "2015",
+            Edition::Rust2018 => "2018",
+            Edition::Rust2021 => "2021",
+        }
+    }
+}
+
+/// Optimization level used when compiling the runtime shared objects
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    /// Optimize for size
+    Size,
+    /// Optimize for size, also turning off loop vectorization
+    SizeMin,
+}
+
+impl OptLevel {
+    /// The value passed to `rustc -Copt-level`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OptLevel::O0 => "0",
+            OptLevel::O1 => "1",
+            OptLevel::O2 => "2",
+            OptLevel::O3 => "3",
+            OptLevel::Size => "s",
+            OptLevel::SizeMin => "z",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct PogoFuncDefinition {
     pub edition: Edition,
+    /// Path of the function starting with its crate, like `my_crate::parse::Parser::next`
     pub name: &'static str,
     pub src: &'static str,
+    /// Source of items the function needs, like the type a method is defined on.
+    /// These are written to the run-time crate ahead of `src`
+    pub deps: &'static [&'static str],
+    /// Number of profiled executions before optimizing, overrides the
+    /// `PGO_EXEC_COUNT` of the group the function is called through
+    pub threshold: Option<usize>,
+    /// Codegen profile for the runtime compiled versions, replaces the one of
+    /// the group the function is called through
+    pub codegen: Option<CodegenProfile>,
+    /// Optimization level for the runtime compiled versions, overrides the
+    /// one in the codegen profile
+    pub opt_level: Option<OptLevel>,
+    /// Calls that run both the optimized library and the natively compiled
+    /// function and compare their results before the library is trusted, 0
+    /// skips verification
+    pub verify: usize,
+    /// Functions exported by the run-time library, resolved once whenever a
+    /// library is loaded. Generic functions export one per instantiation
+    pub symbols: &'static [&'static str],
+    /// The group calls without `_with_group` go through, [`init`] compiles
+    /// it ahead of the first call
+    pub default_group: GroupDefinition,
+}
+
+/// The constants of a [`PogoGroup`], for code that can't name the group's type
+#[derive(Clone, Copy, Debug)]
+pub struct GroupDefinition {
+    pub use_pgo: bool,
+    pub key: u64,
+    pub name: &'static str,
+    pub exec_count: usize,
+    pub codegen: Option<CodegenProfile>,
+}
+
+impl GroupDefinition {
+    pub const fn of<G: PogoGroup>() -> GroupDefinition {
+        GroupDefinition {
+            use_pgo: G::USE_PGO,
+            key: G::KEY,
+            name: G::NAME,
+            exec_count: G::PGO_EXEC_COUNT,
+            codegen: G::CODEGEN,
+        }
+    }
+}
+
+impl PogoFuncDefinition {
+    /// Directory under the working directory used for this function, `::`
+    /// isn't part of a portable file name
+    pub fn dir_name(&self) -> String {
+        self.name.replace("::", "__")
+    }
+
+    /// Add the per-function arguments to a rustc invocation
+    fn add_rustc_args(&self, cmd: &mut std::process::Command) {
+        cmd.args(["--edition", self.edition.as_str()]);
+    }
 }
 
 #[derive(Debug)]
 pub struct PogoFuncCtx {
     pub info: &'static PogoFuncDefinition,
-    pub groups: CHashMap<&'static str, GroupState>,
+    pub groups: GroupTable,
+    /// [`PogoConfig::default_threshold`] when this was initialized
+    pub default_threshold: Option<usize>,
+    /// [`PogoConfig::codegen`] when this was initialized
+    pub default_codegen: CodegenProfile,
+    /// Where requests for this function's groups are queued
+    pub(crate) queue: &'static RequestQueue,
+    /// [`PogoConfig::retention`] when this was initialized
+    pub retention: LibraryRetention,
 }
 
-#[derive(Debug)]
-pub struct GroupState {
-    pub pgo_state: PgoState,
-    pub pgo_count: AtomicUsize,
+impl PogoFuncCtx {
+    /// The context of a function that was just passed to [`init`], with the
+    /// group the plain wrapper calls through already created so it can be
+    /// compiled ahead of the first call. Other groups are created by their
+    /// first call
+    fn new(
+        info: &'static PogoFuncDefinition,
+        default_threshold: Option<usize>,
+        default_codegen: CodegenProfile,
+        queue: &'static RequestQueue,
+        retention: LibraryRetention,
+    ) -> PogoFuncCtx {
+        let ctx = PogoFuncCtx {
+            info,
+            groups: GroupTable::new(),
+            default_threshold,
+            default_codegen,
+            queue,
+            retention,
+        };
+
+        let group = info.default_group;
+        if group.use_pgo {
+            ctx.groups.insert(
+                group.key,
+                group.name,
+                ctx.threshold(group.exec_count),
+                ctx.codegen(group.codegen),
+                info,
+            );
+        }
+        ctx
+    }
+
+    /// Calls to profile in a group before optimizing it, the function's own
+    /// threshold wins over the configured default, which wins over the group's
+    pub fn threshold(&self, group_threshold: usize) -> usize {
+        self.info
+            .threshold
+            .or(self.default_threshold)
+            .unwrap_or(group_threshold)
+    }
+
+    /// How a group's libraries are compiled, the function's own profile wins
+    /// over the group's, which wins over the configured default
+    pub fn codegen(&self, group_codegen: Option<CodegenProfile>) -> CodegenProfile {
+        let profile = self
+            .info
+            .codegen
+            .or(group_codegen)
+            .unwrap_or(self.default_codegen);
+        match self.info.opt_level {
+            Some(opt_level) => profile.opt_level(opt_level),
+            None => profile,
+        }
+    }
+
+    /// Keeps the libraries of this function's groups from being unloaded until
+    /// the guard is dropped, nothing has to be held if they never are
+    #[inline]
+    pub fn pin(&self) -> Option<epoch::Guard> {
+        match self.retention {
+            LibraryRetention::Unload => Some(epoch::pin()),
+            LibraryRetention::KeepResident => None,
+        }
+    }
+
+    /// Replace the state of an optimization group, if the group exists
+    fn set_group_state(
+        &self,
+        group_name: &'static str,
+        state: PgoState,
+        retention: LibraryRetention,
+    ) {
+        if let Some(group) = self.groups.find(group_name) {
+            group.set_state(self.info, state, retention);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -48,91 +260,170 @@ pub enum PgoState {
     /// The shared object is being recompiled with PGO right now, counting executions
     /// is no longer needed.
     Compiling(Library),
+    /// The shared object with PGO applied is loaded but calls still run the
+    /// natively compiled function as well, comparing both results, until
+    /// `verify` of them matched
+    Verifying(Library),
     /// The current shared object is has PGO applied
     Optimized(Library),
-    /// Compiling the shared object failed for some reason
-    CompilationFailed,
+    /// Compiling the shared object failed, the group keeps calling the natively
+    /// compiled function
+    CompilationFailed(Arc<PogoError>),
+    /// The shared object with PGO applied returned something else than the
+    /// natively compiled function, which the group keeps calling. Holds a
+    /// report of the mismatch
+    Rejected(String),
 }
 
 impl PgoState {
-    fn to_compiling(&mut self) {
-        let mut other = PgoState::Uninitialized;
-        std::mem::swap(self, &mut other);
-        match other {
-            PgoState::Uninitialized | PgoState::CompilationFailed => {
-                *self = PgoState::CompilationFailed;
-            }
-            PgoState::GatheringData(lib) | PgoState::Compiling(lib) | PgoState::Optimized(lib) => {
-                *self = PgoState::Compiling(lib)
-            }
+    fn failed(error: PogoError) -> PgoState {
+        PgoState::CompilationFailed(Arc::new(error))
+    }
+
+    /// The state for a newly loaded optimized library, which is verified
+    /// first if the function asks for it
+    fn optimized(info: &PogoFuncDefinition, lib: Library) -> PgoState {
+        if info.verify > 0 {
+            PgoState::Verifying(lib)
+        } else {
+            PgoState::Optimized(lib)
         }
     }
 }
 
-pub fn init<P: Into<PathBuf>>(
-    working_dir: P,
+/// An entry in the link-time registry of `#[pogo]` functions
+///
+/// The `pogo` attribute emits one of these for every annotated function so that
+/// [`init_all`] can find them without the caller listing each one by hand.
+#[derive(Debug)]
+pub struct Registration {
+    pub info: &'static PogoFuncDefinition,
+    pub ctx: &'static ContextCell,
+}
+
+/// Every `#[pogo]` function linked into the final binary, including those
+/// defined in dependencies
+#[distributed_slice]
+pub static REGISTERED_FUNCTIONS: [Registration] = [..];
+
+/// Initialize POGO for every `#[pogo]` function linked into this binary
+pub fn init_all<C: Into<PogoConfig>>(config: C) -> Result<(), Box<dyn Error>> {
+    let funcs: Vec<_> = REGISTERED_FUNCTIONS
+        .iter()
+        .map(|reg| (reg.info, reg.ctx))
+        .collect();
+
+    init(config, &funcs)
+}
+
+pub fn init<C: Into<PogoConfig>>(
+    config: C,
     funcs: &[(&'static PogoFuncDefinition, &'static OnceCell<PogoFuncCtx>)],
 ) -> Result<(), Box<dyn Error>> {
+    let config = config.into().apply_env()?;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    if is_shutting_down() {
+        return Err("pogo has been shut down".into());
+    }
+
+    // Functions sharing a directory would build and load each other's libraries
+    if let Some(dir_name) = shared_dir_name(funcs) {
+        return Err(format!(
+            "more than one #[pogo] function uses the directory {}",
+            dir_name
+        )
+        .into());
+    }
+
     // Initialize the working directory
-    let working_dir: PathBuf = working_dir.into();
+    let working_dir = config.working_dir.clone();
     std::fs::create_dir_all(&working_dir)?;
 
-    // Initialize the background thread
-    let (send, recv) = unbounded();
+    // Initialize the worker pool
+    if REQUEST_QUEUE.get().is_none() {
+        let backend = match &config.backend {
+            Some(backend) => backend.clone(),
+            None => Arc::new(config.rustc_backend().link_info(LinkInfo::discover()?)),
+        };
+        // Nothing could ever be optimized without a working toolchain
+        backend
+            .check_toolchain()
+            .map_err(|err| err as Box<dyn Error>)?;
 
-    match PGO_REQ_SENDER.set(send) {
-        Ok(()) => {
-            // We filled this so initialize the background thread
-            let thread_working_dir = working_dir.clone();
-            std::thread::spawn(|| pgo_worker(thread_working_dir, recv));
+        if REQUEST_QUEUE.set(RequestQueue::new()).is_ok() {
+            // We filled this so start the workers
+            let mut threads = WORKER_THREADS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            for idx in 0..config.workers {
+                let thread_working_dir = working_dir.clone();
+                let backend = backend.clone();
+                let retention = config.retention;
+                let handle = std::thread::Builder::new()
+                    .name(format!("pogo-worker-{}", idx))
+                    .spawn(move || supervise(thread_working_dir, backend, retention))?;
+                threads.push(handle);
+            }
         }
-        Err(_) => {} // Just jump straight to submitting
     }
 
-    let req_sender = PGO_REQ_SENDER.get().unwrap().clone();
+    let queue = REQUEST_QUEUE.get().unwrap();
 
     // Submit all the functions for initialization
     for (func_def, func_ctx_cell) in funcs {
         // Try to initialize the function context
-        let func_ctx_struct = PogoFuncCtx {
-            info: func_def,
-            groups: CHashMap::with_capacity(1),
-        };
-
-        // Submit the global context unconditionally
-        func_ctx_struct.groups.insert_new(
-            Global::NAME,
-            GroupState {
-                pgo_state: PgoState::Uninitialized,
-                pgo_count: AtomicUsize::new(0),
-            },
+        let func_ctx_struct = PogoFuncCtx::new(
+            func_def,
+            config.default_threshold,
+            config.codegen,
+            queue,
+            config.retention,
         );
+        let default_group = func_def.default_group;
 
         match func_ctx_cell.set(func_ctx_struct) {
             Ok(()) => {
                 // Create the source-code for this function
-                std::fs::create_dir_all(working_dir.join(func_def.name))?;
+                let func_dir = working_dir.join(func_def.dir_name());
+                std::fs::create_dir_all(&func_dir)?;
 
                 let mut src_file = std::fs::OpenOptions::new()
                     .write(true)
                     .create(true)
-                    .open(working_dir.join(func_def.name).join("func_src.rs"))?;
+                    .truncate(true)
+                    .open(func_dir.join("func_src.rs"))?;
 
-                src_file.write(
-                    r#"#![crate_type="cdylib"]
-
-#[no_mangle]
-pub "#
-                        .as_bytes(),
-                )?;
+                src_file.write_all(b"#![crate_type=\"cdylib\"]\n\n")?;
+                src_file.write_all(b"#[allow(dead_code)]\nmod __pogo_ffi {\n")?;
+                src_file.write_all(ffi::SRC.as_bytes())?;
+                src_file.write_all(b"}\n\n")?;
+                src_file
+                    .write_all(format!("#[cfg({})]\n", backend::INSTRUMENTED_CFG).as_bytes())?;
+                src_file.write_all(PROFILE_EXPORTS_SRC.as_bytes())?;
+                // The same item can be listed explicitly and also be the type
+                // a method is defined on
+                let mut written_deps: Vec<&str> = Vec::with_capacity(func_def.deps.len());
+                for dep in func_def.deps {
+                    if written_deps.contains(dep) {
+                        continue;
+                    }
+                    written_deps.push(dep);
+                    src_file.write_all(dep.as_bytes())?;
+                    src_file.write_all(b"\n\n")?;
+                }
                 src_file.write_all(func_def.src.as_bytes())?;
                 src_file.flush()?;
 
                 // Submit this for initial compilation
-                req_sender.send(PGORequest::Initial(PGOCompilationInfo {
-                    ctx: &func_ctx_cell.get().unwrap(),
-                    group_name: Global::NAME,
-                }))?;
+                if default_group.use_pgo {
+                    queue.push(PGORequest::Initial(PGOCompilationInfo {
+                        ctx: func_ctx_cell.get().unwrap(),
+                        group_name: default_group.name,
+                    }));
+                }
             }
 
             // This is already initialized, just skip it
@@ -143,215 +434,541 @@ pub "#
     Ok(())
 }
 
-static PGO_REQ_SENDER: OnceCell<Sender<PGORequest>> = OnceCell::new();
+/// A working directory that different functions would share, the same
+/// function can be listed more than once
+fn shared_dir_name(
+    funcs: &[(&'static PogoFuncDefinition, &'static OnceCell<PogoFuncCtx>)],
+) -> Option<String> {
+    let mut dirs: Vec<(String, *const PogoFuncDefinition)> = funcs
+        .iter()
+        .map(|(info, _)| (info.dir_name(), *info as *const _))
+        .collect();
+    dirs.sort_unstable();
+    dirs.dedup();
+    dirs.windows(2)
+        .find(|pair| pair[0].0 == pair[1].0)
+        .map(|pair| pair[0].0.clone())
+}
+
+/// Exports of the instrumented library for writing its profile on demand, the
+/// profiling runtime's own functions are hidden in a `cdylib`
+const PROFILE_EXPORTS_SRC: &str = "mod __pogo_profile {
+    extern \"C\" {
+        fn __llvm_profile_write_file() -> i32;
+        fn __llvm_profile_reset_counters();
+    }
+
+    #[no_mangle]
+    pub extern \"C\" fn __pogo_profile_write_file() -> i32 {
+        unsafe { __llvm_profile_write_file() }
+    }
+
+    #[no_mangle]
+    pub extern \"C\" fn __pogo_profile_reset_counters() {
+        unsafe { __llvm_profile_reset_counters() }
+    }
+}
+
+";
+
+/// Whether the profiling runtime wrote any data into `dir`
+fn has_profile_data(dir: &std::path::Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(Result::ok).any(|entry| {
+                entry.path().extension().is_some_and(|ext| ext == "profraw")
+                    && entry.metadata().is_ok_and(|meta| meta.len() > 0)
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// A snapshot of every `#[pogo]` function linked into the binary and its
+/// optimization groups
+pub fn status() -> Vec<status::FunctionStatus> {
+    REGISTERED_FUNCTIONS
+        .iter()
+        .map(|registration| {
+            let ctx = registration.ctx.get();
+            status::FunctionStatus {
+                name: registration.info.name.to_string(),
+                initialized: ctx.is_some(),
+                groups: ctx
+                    .map(|ctx| ctx.groups.iter().map(GroupState::status).collect())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Every group that failed to compile so far, with the reason
+pub fn errors() -> Vec<GroupError> {
+    REGISTERED_FUNCTIONS
+        .iter()
+        .filter_map(|registration| registration.ctx.get())
+        .flat_map(|ctx| {
+            ctx.groups.iter().filter_map(move |group| {
+                group.error().map(|error| GroupError {
+                    function: ctx.info.name,
+                    group: group.name,
+                    error,
+                })
+            })
+        })
+        .collect()
+}
+
+static REQUEST_QUEUE: OnceCell<RequestQueue> = OnceCell::new();
+
+/// Set by [`shutdown`], nothing new gets compiled after that
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// The threads running [`supervise`], joined by [`shutdown`]
+static WORKER_THREADS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// How often an idle worker checks whether retired libraries can be unloaded
+const UNLOAD_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Compile the first library of a group created after [`init`]
+pub fn submit_initial_request(ctx: &'static PogoFuncCtx, group_name: &'static str) {
+    ctx.queue
+        .push(PGORequest::Initial(PGOCompilationInfo { ctx, group_name }));
+}
 
 pub fn submit_optimization_request(ctx: &'static PogoFuncCtx, group_name: &'static str) {
-    let req_sender = PGO_REQ_SENDER.get().unwrap().clone();
+    ctx.queue.push(PGORequest::Optimized(PGOCompilationInfo {
+        ctx,
+        group_name,
+    }));
+}
 
-    req_sender
-        .send(PGORequest::Optimized(PGOCompilationInfo {
-            ctx,
-            group_name,
-        }))
-        .unwrap();
+/// Hand the outcome of verifying a group's optimized library to the workers
+pub(crate) fn submit_verification(
+    ctx: &'static PogoFuncCtx,
+    group_name: &'static str,
+    mismatch: Option<String>,
+) {
+    ctx.queue.push(PGORequest::Verified(
+        PGOCompilationInfo { ctx, group_name },
+        mismatch,
+    ));
+}
+
+/// Stop the worker threads
+///
+/// Queued compiles are dropped, compiler processes still running are killed
+/// and the threads are joined. Functions keep calling whatever they call right
+/// now, the libraries already loaded stay in use, and calling [`init`]
+/// afterwards is an error.
+pub fn shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+
+    // Wakes up the workers waiting for work
+    if let Some(queue) = REQUEST_QUEUE.get() {
+        queue.close();
+    }
+
+    let threads = std::mem::take(
+        &mut *WORKER_THREADS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    for handle in threads {
+        let _ = handle.join();
+    }
+}
+
+/// Whether [`shutdown`] has been called, a custom
+/// [`CompilerBackend`](backend::CompilerBackend) can poll this to stop long
+/// running steps early
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Calls [`shutdown`] when dropped
+///
+/// Keeping one alive in `main` stops the worker on every way out of it,
+/// including unwinding from a panic.
+#[must_use = "the worker is shut down as soon as the guard is dropped"]
+#[derive(Debug, Default)]
+pub struct ShutdownGuard {
+    _private: (),
+}
+
+impl ShutdownGuard {
+    pub fn new() -> ShutdownGuard {
+        ShutdownGuard::default()
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        shutdown();
+    }
 }
 
-pub fn pgo_worker(working_directory: PathBuf, rec_recv: Receiver<PGORequest>) {
-    while let Ok(req) = rec_recv.recv() {
-        match req {
-            PGORequest::Initial(comp_info) => {
-                println!(
-                    "Got initial compilation request: {}::{}",
-                    comp_info.group_name, comp_info.ctx.info.name
+/// Run the worker, restarting it whenever it panics
+///
+/// The group it was working on would be stuck half way, so it's failed with
+/// the panic message before the restart.
+fn supervise(
+    working_directory: PathBuf,
+    backend: Arc<dyn CompilerBackend>,
+    retention: LibraryRetention,
+) {
+    let in_flight = Mutex::new(None);
+    let queue = REQUEST_QUEUE.get().expect("workers start after the queue");
+
+    loop {
+        let run = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            pgo_worker(
+                working_directory.clone(),
+                backend.clone(),
+                retention,
+                &in_flight,
+                queue,
+            )
+        }));
+        let panic = match run {
+            Ok(()) => return,
+            Err(panic) => panic,
+        };
+
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        event!(error, error = message; "worker panicked, restarting it");
+
+        let comp_info: Option<PGOCompilationInfo> = in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(comp_info) = comp_info {
+            if let Some(group) = comp_info.ctx.groups.find(comp_info.group_name) {
+                group.fail_unfinished(
+                    comp_info.ctx.info,
+                    PogoError::WorkerPanicked { message },
+                    retention,
                 );
+            }
+        }
 
-                let func_base_path = working_directory.join(comp_info.ctx.info.name);
-                let group_working_dir = func_base_path.join(comp_info.group_name);
+        if is_shutting_down() {
+            return;
+        }
+    }
+}
 
-                // Create the directory for this group
-                match std::fs::create_dir_all(&group_working_dir) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                            Some(mut group) => {
-                                group.pgo_state = PgoState::CompilationFailed;
-                            }
-                            None => {}
-                        };
-                        continue;
-                    }
-                }
+/// Cache key for the optimized library of a group, covering everything that
+/// goes into building it
+fn artifact_key(
+    cache: &ArtifactCache,
+    comp_info: &PGOCompilationInfo,
+    group: &GroupState,
+    func_base_path: &std::path::Path,
+) -> Option<String> {
+    let src = std::fs::read(func_base_path.join("func_src.rs")).ok()?;
+    let flags = format!("{:?} {:?}", comp_info.ctx.info.edition, group.codegen);
 
-                let mut cmd = std::process::Command::new("rustc");
-                cmd.arg(format!(
-                    "-Cprofile-generate={}",
-                    group_working_dir.join("profile_data").to_string_lossy()
-                ));
-
-                cmd.arg("--edition");
-                match comp_info.ctx.info.edition {
-                    Edition::Rust2015 => cmd.arg("2015"),
-                    Edition::Rust2018 => cmd.arg("2018"),
-                };
-                cmd.arg("-o");
-                cmd.arg(group_working_dir.join("instrumented.so").as_os_str());
-                cmd.arg(func_base_path.join("func_src.rs").as_os_str());
-
-                println!("{:?}", cmd);
-
-                match cmd.status() {
-                    Ok(exit_status) => {
-                        if exit_status.success() {
-                            match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                                Some(mut group) => {
-                                    group.pgo_state = match Library::new(
-                                        group_working_dir.join("instrumented.so"),
-                                    ) {
-                                        Ok(lib) => PgoState::GatheringData(lib),
-                                        Err(_) => PgoState::CompilationFailed,
-                                    };
-                                }
-                                None => {}
-                            }
-                        } else {
-                            match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                                Some(mut group) => {
-                                    group.pgo_state = PgoState::CompilationFailed;
-                                }
-                                None => {}
+    Some(cache.key(&[&src, flags.as_bytes(), comp_info.group_name.as_bytes()]))
+}
+
+fn pgo_worker(
+    working_directory: PathBuf,
+    backend: Arc<dyn CompilerBackend>,
+    retention: LibraryRetention,
+    in_flight: &Mutex<Option<PGOCompilationInfo>>,
+    queue: &RequestQueue,
+) {
+    let cache = ArtifactCache::new(working_directory.join("cache"), &*backend);
+
+    loop {
+        // Retired libraries can only be unloaded once calls have moved on, so
+        // keep checking on them while waiting for requests
+        let req = if unload_retired() {
+            match queue.pop_timeout(UNLOAD_INTERVAL) {
+                Pop::Request(req) => req,
+                Pop::TimedOut => continue,
+                Pop::Closed => break,
+            }
+        } else {
+            match queue.pop() {
+                Some(req) => req,
+                None => break,
+            }
+        };
+
+        *in_flight.lock().unwrap_or_else(PoisonError::into_inner) = Some(req.info());
+        process_request(
+            req,
+            &working_directory,
+            &*backend,
+            cache.as_ref(),
+            retention,
+        );
+    }
+    unload_retired();
+}
+
+/// Carry out a single request, moving its group on to the next state
+fn process_request(
+    req: PGORequest,
+    working_directory: &Path,
+    backend: &dyn CompilerBackend,
+    cache: Option<&ArtifactCache>,
+    retention: LibraryRetention,
+) {
+    match req {
+        PGORequest::Initial(comp_info) => {
+            event!(
+                debug,
+                function = comp_info.ctx.info.name,
+                group = comp_info.group_name;
+                "initial compilation request"
+            );
+
+            let group = match comp_info.ctx.groups.find(comp_info.group_name) {
+                Some(group) if group.is_uninitialized() => group,
+                _ => return,
+            };
+
+            let func_base_path = working_directory.join(comp_info.ctx.info.dir_name());
+            let group_working_dir = func_base_path.join(comp_info.group_name);
+
+            // Create the directory for this group
+            if let Err(error) = std::fs::create_dir_all(&group_working_dir) {
+                comp_info.ctx.set_group_state(
+                    comp_info.group_name,
+                    PgoState::failed(PogoError::CreateDir {
+                        path: group_working_dir,
+                        error,
+                    }),
+                    retention,
+                );
+                return;
+            }
+
+            // A previous run may have already optimized this exact function
+            if let Some(cache) = &cache {
+                if let Some(key) = artifact_key(cache, &comp_info, group, &func_base_path) {
+                    if let Some(cached) = cache.lookup(&key) {
+                        match Library::new(&cached) {
+                            Ok(lib) => {
+                                event!(
+                                    debug,
+                                    function = comp_info.ctx.info.name,
+                                    group = comp_info.group_name,
+                                    path = cached;
+                                    "loaded cached optimized library"
+                                );
+                                group.record_optimized(cached, None);
+                                comp_info.ctx.set_group_state(
+                                    comp_info.group_name,
+                                    PgoState::optimized(comp_info.ctx.info, lib),
+                                    retention,
+                                );
+                                return;
                             }
+                            Err(_) => cache.evict(&key),
                         }
                     }
-                    Err(_) => match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                        Some(mut group) => {
-                            group.pgo_state = PgoState::CompilationFailed;
-                        }
-                        None => {}
-                    },
                 }
             }
 
-            PGORequest::Optimized(comp_info) => {
-                println!(
-                    "Got optimized compilation request: {}::{}",
-                    comp_info.group_name, comp_info.ctx.info.name
-                );
+            let src_path = func_base_path.join("func_src.rs");
+            let instrumented_path = group_working_dir.join("instrumented.so");
+            let job = CompileJob {
+                info: comp_info.ctx.info,
+                group_name: comp_info.group_name,
+                codegen: group.codegen,
+                src: &src_path,
+                output: &instrumented_path,
+            };
 
-                // Update to indicate that we are currently compiling
-                match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                    Some(mut group) => {
-                        group.pgo_state.to_compiling();
-                        match group.pgo_state {
-                            PgoState::CompilationFailed => {
-                                continue;
-                            }
-                            _ => {}
-                        };
-                    }
-                    None => {
-                        continue;
+            // Raw profiles left by an earlier run may be for different code
+            let profile_data_dir = group_working_dir.join("profile_data");
+            let _ = std::fs::remove_dir_all(&profile_data_dir);
+
+            let _phase = phase_span!("instrument", comp_info.ctx.info.name, comp_info.group_name);
+            let started = Instant::now();
+            let new_state = match backend.instrument(&job, &profile_data_dir) {
+                Ok(()) => match Library::new(&instrumented_path) {
+                    Ok(lib) => {
+                        event!(
+                            debug,
+                            function = comp_info.ctx.info.name,
+                            group = comp_info.group_name,
+                            phase = "instrument",
+                            duration = started.elapsed();
+                            "built instrumented library"
+                        );
+                        group.record_instrumented(instrumented_path, started.elapsed());
+                        PgoState::GatheringData(lib)
                     }
-                }
+                    Err(error) => PgoState::failed(PogoError::Load {
+                        path: instrumented_path,
+                        error,
+                    }),
+                },
+                Err(error) => PgoState::failed(PogoError::from_backend(error)),
+            };
+            comp_info
+                .ctx
+                .set_group_state(comp_info.group_name, new_state, retention);
+        }
 
-                let func_base_path = working_directory.join(comp_info.ctx.info.name);
-                let group_working_dir = func_base_path.join(comp_info.group_name);
-                let profile_data_dir = group_working_dir.join("profile_data");
+        PGORequest::Optimized(comp_info) => {
+            event!(
+                debug,
+                function = comp_info.ctx.info.name,
+                group = comp_info.group_name;
+                "optimized compilation request"
+            );
 
-                // Gather all the data together
-                let mut cmd = std::process::Command::new("cargo");
-                cmd.args(&["profdata", "--"]);
+            // Update to indicate that we are currently compiling, requests
+            // from an earlier profiling phase are dropped here
+            let group = match comp_info.ctx.groups.find(comp_info.group_name) {
+                Some(group) if group.begin_compiling() => group,
+                _ => return,
+            };
 
-                cmd.arg("merge");
-                cmd.arg("-o");
-                cmd.arg(group_working_dir.join("pgo.profdata"));
-                cmd.arg(profile_data_dir.as_os_str());
+            let func_base_path = working_directory.join(comp_info.ctx.info.dir_name());
+            let group_working_dir = func_base_path.join(comp_info.group_name);
+            let profile_data_dir = group_working_dir.join("profile_data");
+            let profile_path = group_working_dir.join("pgo.profdata");
+
+            let started = Instant::now();
+
+            // Gather all the data together, the profiling runtime only
+            // writes it by itself once the library is unloaded
+            let merged = {
+                let _phase = phase_span!("merge", comp_info.ctx.info.name, comp_info.group_name);
+                if !group.write_profile() {
+                    Err(PogoError::Merge {
+                        profile_dir: profile_data_dir,
+                        reason: "the profiling runtime failed to write it".to_string(),
+                    })
+                } else if !has_profile_data(&profile_data_dir) {
+                    Err(PogoError::Merge {
+                        profile_dir: profile_data_dir,
+                        reason: "no profile data was written".to_string(),
+                    })
+                } else {
+                    backend
+                        .merge_profiles(&profile_data_dir, &profile_path)
+                        .map_err(PogoError::from_backend)
+                }
+            };
+            if let Err(error) = merged {
+                comp_info.ctx.set_group_state(
+                    comp_info.group_name,
+                    PgoState::failed(error),
+                    retention,
+                );
+                return;
+            }
 
-                println!("{:?}", cmd);
+            // Compile using the gathered data
+            let src_path = func_base_path.join("func_src.rs");
+            let optimized_path = group_working_dir.join("optimized.so");
+            let job = CompileJob {
+                info: comp_info.ctx.info,
+                group_name: comp_info.group_name,
+                codegen: group.codegen,
+                src: &src_path,
+                output: &optimized_path,
+            };
 
-                match cmd.status() {
-                    Ok(exit_status) => {
-                        if !exit_status.success() {
-                            match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                                Some(mut group) => {
-                                    group.pgo_state = PgoState::CompilationFailed;
+            let _phase = phase_span!("optimize", comp_info.ctx.info.name, comp_info.group_name);
+            let new_state = match backend.optimize(&job, &profile_path) {
+                Ok(()) => {
+                    match Library::new(&optimized_path) {
+                        Ok(lib) => {
+                            // Failing to cache only costs the next run a recompile
+                            if let Some(cache) = &cache {
+                                if let Some(key) =
+                                    artifact_key(cache, &comp_info, group, &func_base_path)
+                                {
+                                    let _ = cache.store(&key, &optimized_path);
                                 }
-                                None => {}
                             }
-                            continue;
+                            event!(
+                                debug,
+                                function = comp_info.ctx.info.name,
+                                group = comp_info.group_name,
+                                phase = "optimize",
+                                duration = started.elapsed();
+                                "built optimized library"
+                            );
+                            group.record_optimized(optimized_path, Some(started.elapsed()));
+                            PgoState::optimized(comp_info.ctx.info, lib)
                         }
-                    }
-                    Err(_) => {
-                        match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                            Some(mut group) => {
-                                group.pgo_state = PgoState::CompilationFailed;
-                            }
-                            None => {}
-                        };
-                        continue;
+                        Err(error) => PgoState::failed(PogoError::Load {
+                            path: optimized_path,
+                            error,
+                        }),
                     }
                 }
+                Err(error) => PgoState::failed(PogoError::from_backend(error)),
+            };
+            comp_info
+                .ctx
+                .set_group_state(comp_info.group_name, new_state, retention);
+        }
 
-                // Compile using the gathered data
-                let mut cmd = std::process::Command::new("rustc");
-                cmd.arg(format!(
-                    "-Cprofile-use={}",
-                    group_working_dir.join("pgo.profdata").to_string_lossy()
-                ));
-
-                cmd.arg("--edition");
-                match comp_info.ctx.info.edition {
-                    Edition::Rust2015 => cmd.arg("2015"),
-                    Edition::Rust2018 => cmd.arg("2018"),
-                };
-                cmd.arg("-o");
-                cmd.arg(group_working_dir.join("optimized.so").as_os_str());
-                cmd.arg(func_base_path.join("func_src.rs").as_os_str());
-
-                println!("{:?}", cmd);
-
-                match cmd.status() {
-                    Ok(exit_status) => {
-                        if exit_status.success() {
-                            match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                                Some(mut group) => {
-                                    group.pgo_state = match Library::new(
-                                        group_working_dir.join("optimized.so"),
-                                    ) {
-                                        Ok(lib) => PgoState::Optimized(lib),
-                                        Err(_) => PgoState::CompilationFailed,
-                                    };
-                                }
-                                None => {}
-                            }
-                        } else {
-                            match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                                Some(mut group) => {
-                                    group.pgo_state = PgoState::CompilationFailed;
-                                }
-                                None => {}
-                            }
-                        }
+        PGORequest::Verified(comp_info, mismatch) => {
+            let group = match comp_info.ctx.groups.find(comp_info.group_name) {
+                Some(group) => group,
+                None => return,
+            };
+
+            // A cached library is verified again every run, a rejected
+            // one shouldn't be loaded in the first place
+            if mismatch.is_some() {
+                if let Some(cache) = &cache {
+                    let func_base_path = working_directory.join(comp_info.ctx.info.dir_name());
+                    if let Some(key) = artifact_key(cache, &comp_info, group, &func_base_path) {
+                        cache.evict(&key);
                     }
-                    Err(_) => match comp_info.ctx.groups.get_mut(comp_info.group_name) {
-                        Some(mut group) => {
-                            group.pgo_state = PgoState::CompilationFailed;
-                        }
-                        None => {}
-                    },
                 }
             }
+
+            group.finish_verifying(comp_info.ctx.info, mismatch, retention);
         }
     }
-
-    // We need to identify a graceful shutdown but for now... we will jsut die
-    panic!("PGO Worker failed on an error");
 }
 
 pub enum PGORequest {
     Initial(PGOCompilationInfo),
     Optimized(PGOCompilationInfo),
+    /// Verifying the optimized library ended, rejecting it with the report if
+    /// a result didn't match
+    Verified(PGOCompilationInfo, Option<String>),
+}
+
+impl PGORequest {
+    fn info(&self) -> PGOCompilationInfo {
+        match self {
+            PGORequest::Initial(comp_info)
+            | PGORequest::Optimized(comp_info)
+            | PGORequest::Verified(comp_info, _) => *comp_info,
+        }
+    }
+
+    /// How hot the group is, used to order the queue
+    fn call_rate(&self) -> f64 {
+        let comp_info = self.info();
+        comp_info
+            .ctx
+            .groups
+            .find(comp_info.group_name)
+            .map_or(0.0, GroupState::call_rate)
+    }
 }
 
+#[derive(Clone, Copy)]
 pub struct PGOCompilationInfo {
     ctx: &'static PogoFuncCtx,
     group_name: &'static str,
@@ -361,6 +978,13 @@ pub trait PogoGroup {
     const USE_PGO: bool = true;
     const NAME: &'static str;
     const PGO_EXEC_COUNT: usize;
+    /// How the group's libraries are compiled, [`PogoConfig::codegen`] when
+    /// `None`
+    const CODEGEN: Option<CodegenProfile> = None;
+
+    /// Where the group's state is kept in every function's [`GroupTable`]
+    #[doc(hidden)]
+    const KEY: u64 = dispatch::group_key(Self::NAME);
 }
 
 pub struct Global;
@@ -375,3 +999,480 @@ impl PogoGroup for NoPGO {
     const NAME: &'static str = "__NO_PGO";
     const PGO_EXEC_COUNT: usize = 0;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(name: &'static str) -> &'static PogoFuncDefinition {
+        definition_in::<Global>(name)
+    }
+
+    fn definition_in<G: PogoGroup>(name: &'static str) -> &'static PogoFuncDefinition {
+        Box::leak(Box::new(PogoFuncDefinition {
+            edition: Edition::Rust2018,
+            name,
+            src: "pub fn f() {}",
+            deps: &[],
+            threshold: None,
+            codegen: None,
+            opt_level: None,
+            verify: 0,
+            symbols: &["__pogo_f"],
+            default_group: GroupDefinition::of::<G>(),
+        }))
+    }
+
+    fn context_cell() -> &'static ContextCell {
+        Box::leak(Box::new(ContextCell::new()))
+    }
+
+    #[test]
+    fn dir_names_include_the_module() {
+        assert_eq!(definition("app::a::f").dir_name(), "app__a__f");
+        assert_eq!(definition("app::a::T::f").dir_name(), "app__a__T__f");
+    }
+
+    #[test]
+    fn functions_sharing_a_directory() {
+        let a = (definition("app::a::f"), context_cell());
+        let b = (definition("app::b::f"), context_cell());
+        assert_eq!(shared_dir_name(&[a, b, a]), None);
+
+        let clash = (definition("app__a::f"), context_cell());
+        assert_eq!(
+            shared_dir_name(&[a, b, clash]).as_deref(),
+            Some("app__a__f")
+        );
+    }
+
+    fn queue() -> &'static RequestQueue {
+        Box::leak(Box::new(RequestQueue::new()))
+    }
+
+    struct Small;
+    impl PogoGroup for Small {
+        const NAME: &'static str = "small";
+        const PGO_EXEC_COUNT: usize = 10;
+    }
+
+    #[test]
+    fn init_creates_the_default_group() {
+        let release = CodegenProfile::release();
+        let ctx = PogoFuncCtx::new(
+            definition("app::f"),
+            None,
+            release,
+            queue(),
+            LibraryRetention::Unload,
+        );
+        let names: Vec<_> = ctx.groups.iter().map(|group| group.name).collect();
+        assert_eq!(names, [Global::NAME]);
+
+        let ctx = PogoFuncCtx::new(
+            definition_in::<Small>("app::f"),
+            None,
+            release,
+            queue(),
+            LibraryRetention::Unload,
+        );
+        let names: Vec<_> = ctx.groups.iter().map(|group| group.name).collect();
+        assert_eq!(names, [Small::NAME]);
+        assert_eq!(ctx.groups.get(Small::KEY).unwrap().status().threshold, 10);
+    }
+
+    #[test]
+    fn init_skips_groups_without_pgo() {
+        let release = CodegenProfile::release();
+        let ctx = PogoFuncCtx::new(
+            definition_in::<NoPGO>("app::f"),
+            None,
+            release,
+            queue(),
+            LibraryRetention::Unload,
+        );
+        assert_eq!(ctx.groups.iter().count(), 0);
+    }
+
+    /// A library exporting `__pogo_f`, built once per test run
+    fn fixture_library() -> &'static Path {
+        static FIXTURE: OnceCell<PathBuf> = OnceCell::new();
+        FIXTURE.get_or_init(|| {
+            let dir = std::env::temp_dir().join("pogo-test-fixture");
+            std::fs::create_dir_all(&dir).unwrap();
+            let id = std::process::id();
+            let src = dir.join(format!("fixture-{}.rs", id));
+            std::fs::write(
+                &src,
+                "#[no_mangle]\npub extern \"C\" fn __pogo_f(x: u32) -> u32 { x + 1 }\n",
+            )
+            .unwrap();
+            let built = dir.join(format!("fixture-{}.so", id));
+            let status = std::process::Command::new("rustc")
+                .args(["--crate-type", "cdylib", "-o"])
+                .arg(&built)
+                .arg(&src)
+                .status()
+                .unwrap();
+            let _ = std::fs::remove_file(&src);
+            assert!(status.success(), "building the fixture library failed");
+
+            // Other test runs may be reading the previous one
+            let output = dir.join("fixture.so");
+            std::fs::rename(&built, &output).unwrap();
+            output
+        })
+    }
+
+    /// Writes the fixture library wherever a library is asked for, and fails
+    /// to instrument functions named `...::broken`
+    #[derive(Default)]
+    struct FakeBackend {
+        instrumented: std::sync::atomic::AtomicUsize,
+        optimized: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CompilerBackend for FakeBackend {
+        fn instrument(&self, job: &CompileJob, profile_dir: &Path) -> backend::BackendResult {
+            if job.info.name.ends_with("::broken") {
+                return Err("no instrumenting this".into());
+            }
+            self.instrumented.fetch_add(1, Ordering::SeqCst);
+            std::fs::copy(fixture_library(), job.output)?;
+            std::fs::create_dir_all(profile_dir)?;
+            std::fs::write(profile_dir.join("default.profraw"), b"counters")?;
+            Ok(())
+        }
+
+        fn merge_profiles(&self, _profile_dir: &Path, output: &Path) -> backend::BackendResult {
+            std::fs::write(output, b"merged")?;
+            Ok(())
+        }
+
+        fn optimize(&self, job: &CompileJob, profile: &Path) -> backend::BackendResult {
+            assert!(profile.is_file());
+            self.optimized.fetch_add(1, Ordering::SeqCst);
+            std::fs::copy(fixture_library(), job.output)?;
+            Ok(())
+        }
+
+        fn identity(&self) -> Option<Vec<u8>> {
+            Some(b"fake".to_vec())
+        }
+    }
+
+    /// A worker that's driven one request at a time
+    struct Harness {
+        dir: PathBuf,
+        backend: FakeBackend,
+        cache: ArtifactCache,
+        queue: &'static RequestQueue,
+    }
+
+    impl Harness {
+        fn new(test: &str) -> Harness {
+            let dir =
+                std::env::temp_dir().join(format!("pogo-test-{}-{}", std::process::id(), test));
+            let _ = std::fs::remove_dir_all(&dir);
+            let backend = FakeBackend::default();
+            let cache = ArtifactCache::new(dir.join("cache"), &backend).unwrap();
+            Harness {
+                dir,
+                backend,
+                cache,
+                queue: queue(),
+            }
+        }
+
+        /// Set up a function the way `init` does
+        fn function(&self, info: PogoFuncDefinition) -> &'static PogoFuncCtx {
+            let info: &'static PogoFuncDefinition = Box::leak(Box::new(info));
+            let func_dir = self.dir.join(info.dir_name());
+            std::fs::create_dir_all(&func_dir).unwrap();
+            std::fs::write(func_dir.join("func_src.rs"), info.src).unwrap();
+            Box::leak(Box::new(PogoFuncCtx::new(
+                info,
+                None,
+                CodegenProfile::release(),
+                self.queue,
+                LibraryRetention::Unload,
+            )))
+        }
+
+        fn initial(&self, ctx: &'static PogoFuncCtx) {
+            self.process(PGORequest::Initial(PGOCompilationInfo {
+                ctx,
+                group_name: Global::NAME,
+            }));
+        }
+
+        fn process(&self, req: PGORequest) {
+            process_request(
+                req,
+                &self.dir,
+                &self.backend,
+                Some(&self.cache),
+                LibraryRetention::Unload,
+            );
+        }
+
+        /// Process everything queued, returning what kind of requests they were
+        fn process_queued(&self) -> Vec<&'static str> {
+            let mut kinds = Vec::new();
+            for req in self.queue.drain() {
+                kinds.push(match req {
+                    PGORequest::Initial(_) => "initial",
+                    PGORequest::Optimized(_) => "optimized",
+                    PGORequest::Verified(_, None) => "verified",
+                    PGORequest::Verified(_, Some(_)) => "mismatch",
+                });
+                self.process(req);
+            }
+            kinds
+        }
+
+        fn cache_key(&self, ctx: &'static PogoFuncCtx) -> String {
+            let comp_info = PGOCompilationInfo {
+                ctx,
+                group_name: Global::NAME,
+            };
+            let func_dir = self.dir.join(ctx.info.dir_name());
+            artifact_key(&self.cache, &comp_info, group(ctx), &func_dir).unwrap()
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn group(ctx: &'static PogoFuncCtx) -> &'static GroupState {
+        ctx.groups.get(Global::KEY).unwrap()
+    }
+
+    fn state(ctx: &'static PogoFuncCtx) -> status::StateKind {
+        group(ctx).status().state
+    }
+
+    fn profiled(name: &'static str, threshold: usize, verify: usize) -> PogoFuncDefinition {
+        PogoFuncDefinition {
+            threshold: Some(threshold),
+            verify,
+            ..*definition(name)
+        }
+    }
+
+    /// Make `calls` calls to the function from each of `threads` threads
+    fn call_from_threads(ctx: &'static PogoFuncCtx, threads: usize, calls: usize) {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..calls {
+                        group(ctx).record_execution(ctx);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn worker_profiles_then_optimizes() {
+        let harness = Harness::new("profile");
+        let ctx = harness.function(profiled("app::f", 10, 0));
+        assert_eq!(state(ctx), status::StateKind::Uninitialized);
+
+        harness.initial(ctx);
+        assert_eq!(state(ctx), status::StateKind::GatheringData);
+        assert!(!group(ctx).funcs.get(0).is_null());
+
+        call_from_threads(ctx, 1, 10);
+        assert!(harness.process_queued().is_empty());
+        call_from_threads(ctx, 1, 1);
+        assert_eq!(harness.process_queued(), ["optimized"]);
+
+        assert_eq!(state(ctx), status::StateKind::Optimized);
+        assert!(!group(ctx).funcs.get(0).is_null());
+        assert_eq!(harness.backend.instrumented.load(Ordering::SeqCst), 1);
+        assert_eq!(harness.backend.optimized.load(Ordering::SeqCst), 1);
+        assert!(harness.cache.lookup(&harness.cache_key(ctx)).is_some());
+
+        // Requests that arrive after the group moved on are dropped
+        harness.initial(ctx);
+        harness.process(PGORequest::Optimized(PGOCompilationInfo {
+            ctx,
+            group_name: Global::NAME,
+        }));
+        assert_eq!(state(ctx), status::StateKind::Optimized);
+        assert_eq!(harness.backend.instrumented.load(Ordering::SeqCst), 1);
+        assert_eq!(harness.backend.optimized.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn one_optimization_request_per_profiling_phase() {
+        let harness = Harness::new("single-request");
+        let ctx = harness.function(profiled("app::f", 100, 0));
+        harness.initial(ctx);
+
+        call_from_threads(ctx, 8, 1_000);
+        assert_eq!(harness.process_queued(), ["optimized"]);
+        assert_eq!(state(ctx), status::StateKind::Optimized);
+
+        // Calls after profiling ended don't ask again
+        call_from_threads(ctx, 8, 1_000);
+        assert!(harness.process_queued().is_empty());
+    }
+
+    #[test]
+    fn failed_compile_keeps_the_native_function() {
+        let harness = Harness::new("failed");
+        let ctx = harness.function(profiled("app::broken", 10, 0));
+        harness.initial(ctx);
+
+        assert_eq!(state(ctx), status::StateKind::CompilationFailed);
+        assert!(group(ctx).error().is_some());
+        assert!(group(ctx).funcs.get(0).is_null());
+    }
+
+    #[test]
+    fn cached_library_skips_profiling() {
+        let harness = Harness::new("cache-hit");
+        let first = harness.function(profiled("app::first", 0, 0));
+        harness.initial(first);
+        call_from_threads(first, 1, 1);
+        assert_eq!(harness.process_queued(), ["optimized"]);
+
+        // Same source, edition, codegen and group
+        let second = harness.function(profiled("app::second", 0, 0));
+        harness.initial(second);
+        assert_eq!(state(second), status::StateKind::Optimized);
+        assert_eq!(group(second).status().optimize_duration, None);
+        assert_eq!(harness.backend.instrumented.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unloadable_cached_library_is_evicted() {
+        let harness = Harness::new("cache-evict");
+        let first = harness.function(profiled("app::first", 0, 0));
+        harness.initial(first);
+        call_from_threads(first, 1, 1);
+        harness.process_queued();
+
+        let key = harness.cache_key(first);
+        std::fs::write(harness.cache.lookup(&key).unwrap(), b"not a library").unwrap();
+
+        let second = harness.function(profiled("app::second", 0, 0));
+        harness.initial(second);
+        assert_eq!(state(second), status::StateKind::GatheringData);
+        assert!(harness.cache.lookup(&key).is_none());
+        assert_eq!(harness.backend.instrumented.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn verified_library_is_trusted() {
+        let harness = Harness::new("verify-accept");
+        let ctx = harness.function(profiled("app::f", 0, 50));
+        harness.initial(ctx);
+        call_from_threads(ctx, 1, 1);
+        harness.process_queued();
+        assert_eq!(state(ctx), status::StateKind::Verifying);
+        assert!(group(ctx).is_verifying());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        group(ctx).record_verification(ctx, &1, &1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(harness.process_queued(), ["verified"]);
+        assert_eq!(state(ctx), status::StateKind::Optimized);
+        assert!(!group(ctx).is_verifying());
+        assert!(!group(ctx).funcs.get(0).is_null());
+    }
+
+    #[test]
+    fn mismatching_library_is_rejected() {
+        let harness = Harness::new("verify-reject");
+        let ctx = harness.function(profiled("app::f", 0, 50));
+        harness.initial(ctx);
+        call_from_threads(ctx, 1, 1);
+        harness.process_queued();
+        let key = harness.cache_key(ctx);
+        assert!(harness.cache.lookup(&key).is_some());
+
+        let handles: Vec<_> = (0..8)
+            .map(|idx| std::thread::spawn(move || group(ctx).record_verification(ctx, &0, &idx)))
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // Calls stop using the library right away
+        assert!(group(ctx).funcs.get(0).is_null());
+
+        assert_eq!(harness.process_queued(), ["mismatch"]);
+        assert_eq!(state(ctx), status::StateKind::Rejected);
+        assert!(group(ctx).status().last_error.is_some());
+        assert!(harness.cache.lookup(&key).is_none());
+    }
+
+    #[test]
+    fn keep_resident_calls_dont_pin() {
+        let release = CodegenProfile::release();
+        let unload = PogoFuncCtx::new(
+            definition("app::f"),
+            None,
+            release,
+            queue(),
+            LibraryRetention::Unload,
+        );
+        assert!(unload.pin().is_some());
+        let resident = PogoFuncCtx::new(
+            definition("app::f"),
+            None,
+            release,
+            queue(),
+            LibraryRetention::KeepResident,
+        );
+        assert!(resident.pin().is_none());
+    }
+
+    fn is_loaded(path: &Path) -> bool {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.contains(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn replaced_library_is_unloaded_by_a_worker() {
+        let harness = Harness::new("unload");
+        let ctx = harness.function(profiled("app::f", 0, 0));
+        harness.initial(ctx);
+        let instrumented = group(ctx).status().instrumented_library.unwrap();
+        assert!(is_loaded(&instrumented));
+        call_from_threads(ctx, 1, 1);
+        assert_eq!(harness.process_queued(), ["optimized"]);
+
+        // Calls collecting the epoch's garbage only hand the library over
+        for _ in 0..1_000 {
+            epoch::pin().flush();
+        }
+        assert!(is_loaded(&instrumented));
+
+        for _ in 0..1_000 {
+            if !unload_retired() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!is_loaded(&instrumented));
+    }
+}
@@ -0,0 +1,197 @@
+//! Linking information so run-time compiled functions can use the same crates
+//! as the host binary
+//!
+//! The information is produced at build time by `cargo pogo build`, which
+//! writes a `<executable>.pogo-link` file next to every executable it builds.
+
+use crate::cache::Fnv1a;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// File extension appended to an executable's path for its link information
+pub const LINK_INFO_EXTENSION: &str = "pogo-link";
+
+/// Environment variable overriding where the link information is read from
+pub const LINK_INFO_ENV: &str = "POGO_LINK_INFO";
+
+/// The `--extern`, `-L` and `--cfg` arguments the host crate was compiled with
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LinkInfo {
+    /// Crate name and the rlib (or proc-macro library) it resolves to
+    pub externs: Vec<(String, PathBuf)>,
+    /// Values for `-L`, including the `kind=` prefix if any
+    pub search_paths: Vec<String>,
+    /// Values for `--cfg`, like `feature="std"`
+    pub cfgs: Vec<String>,
+}
+
+impl LinkInfo {
+    /// Find the link information for the running executable
+    ///
+    /// `POGO_LINK_INFO` takes precedence over the file next to the executable,
+    /// if neither exists the run-time crates are compiled without any externs.
+    pub fn discover() -> std::io::Result<LinkInfo> {
+        if let Some(path) = std::env::var_os(LINK_INFO_ENV) {
+            return LinkInfo::load(path);
+        }
+
+        let mut path = std::env::current_exe()?.into_os_string();
+        path.push(".");
+        path.push(LINK_INFO_EXTENSION);
+        let path = PathBuf::from(path);
+
+        if path.exists() {
+            LinkInfo::load(path)
+        } else {
+            Ok(LinkInfo::default())
+        }
+    }
+
+    /// Read link information from a file written by [`LinkInfo::save`]
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<LinkInfo> {
+        let file = std::fs::File::open(path)?;
+        let mut info = LinkInfo::default();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (kind, value) = line.split_once(' ').ok_or_else(|| invalid_line(line))?;
+            match kind {
+                "extern" => {
+                    let (name, path) = value.split_once('=').ok_or_else(|| invalid_line(line))?;
+                    info.externs.push((name.to_string(), PathBuf::from(path)));
+                }
+                "search" => info.search_paths.push(value.to_string()),
+                "cfg" => info.cfgs.push(value.to_string()),
+                _ => return Err(invalid_line(line)),
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Write the link information in the format read by [`LinkInfo::load`]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+
+        writeln!(file, "# Generated by cargo-pogo, do not edit")?;
+        for (name, path) in &self.externs {
+            writeln!(file, "extern {}={}", name, path.display())?;
+        }
+        for search_path in &self.search_paths {
+            writeln!(file, "search {}", search_path)?;
+        }
+        for cfg in &self.cfgs {
+            writeln!(file, "cfg {}", cfg)?;
+        }
+
+        file.flush()
+    }
+
+    /// Hashes of the contents of every extern, for the artifact cache key
+    ///
+    /// The paths alone aren't enough: cargo keeps the file name of a path
+    /// dependency or the package's own library when their code changes.
+    pub(crate) fn fingerprint(&self) -> String {
+        let mut fingerprint = String::new();
+        for (name, path) in &self.externs {
+            let hash = match hash_file(path) {
+                Ok(hash) => format!("{:016x}", hash),
+                // Compiling fails anyway, it only mustn't match another key
+                Err(error) => format!("unreadable ({})", error),
+            };
+            fingerprint.push_str(&format!("{}={} ", name, hash));
+        }
+        fingerprint
+    }
+
+    /// Add the linking arguments to a rustc invocation
+    pub fn add_rustc_args(&self, cmd: &mut std::process::Command) {
+        for (name, path) in &self.externs {
+            let mut arg = std::ffi::OsString::from(name);
+            arg.push("=");
+            arg.push(path);
+            cmd.arg("--extern").arg(arg);
+        }
+        for search_path in &self.search_paths {
+            cmd.arg("-L").arg(search_path);
+        }
+        for cfg in &self.cfgs {
+            cmd.arg("--cfg").arg(cfg);
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> std::io::Result<u64> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Fnv1a::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..read]);
+    }
+}
+
+fn invalid_line(line: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid pogo link information line: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pogo-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let info = LinkInfo {
+            externs: vec![
+                ("rand".to_string(), PathBuf::from("/deps/librand-1a2b.rlib")),
+                (
+                    "my_lib".to_string(),
+                    PathBuf::from("/with space/libmy_lib.rlib"),
+                ),
+            ],
+            search_paths: vec!["dependency=/deps".to_string(), "/native".to_string()],
+            cfgs: vec!["feature=\"std\"".to_string(), "feature=\"a b\"".to_string()],
+        };
+
+        let path = temp_path("round-trip.pogo-link");
+        info.save(&path).unwrap();
+        let loaded = LinkInfo::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), info);
+    }
+
+    #[test]
+    fn empty_round_trip() {
+        let path = temp_path("empty.pogo-link");
+        LinkInfo::default().save(&path).unwrap();
+        let loaded = LinkInfo::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), LinkInfo::default());
+    }
+
+    #[test]
+    fn load_rejects_invalid_lines() {
+        for contents in ["extern rand", "link foo", "cfg"] {
+            let path = temp_path("invalid.pogo-link");
+            std::fs::write(&path, contents).unwrap();
+            let loaded = LinkInfo::load(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Requests waiting for a worker, hottest group first
+
+use crate::PGORequest;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// The queue the worker pool takes requests from
+///
+/// Requests are ordered by how often their group was called while profiling,
+/// measured when they are queued. Requests for groups that haven't been
+/// profiled yet come after those, and equally hot requests are taken in the
+/// order they were queued.
+pub(crate) struct RequestQueue<R = PGORequest> {
+    state: Mutex<QueueState<R>>,
+    ready: Condvar,
+}
+
+struct QueueState<R> {
+    pending: Vec<Queued<R>>,
+    next_seq: u64,
+    closed: bool,
+}
+
+/// What waiting for a request with [`RequestQueue::pop_timeout`] ended with
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Pop<R> {
+    Request(R),
+    TimedOut,
+    Closed,
+}
+
+struct Queued<R> {
+    seq: u64,
+    /// Calls per second of the group
+    rate: f64,
+    req: R,
+}
+
+impl RequestQueue {
+    /// Queue a request, dropped if the queue has been closed
+    pub(crate) fn push(&self, req: PGORequest) {
+        let rate = req.call_rate();
+        self.push_with_rate(req, rate);
+    }
+}
+
+impl<R> RequestQueue<R> {
+    pub(crate) fn new() -> RequestQueue<R> {
+        RequestQueue {
+            state: Mutex::new(QueueState {
+                pending: Vec::new(),
+                next_seq: 0,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState<R>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push_with_rate(&self, req: R, rate: f64) {
+        let mut state = self.lock();
+        if state.closed {
+            return;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.pending.push(Queued { seq, rate, req });
+        self.ready.notify_one();
+    }
+
+    /// Wait for the hottest request, `None` once the queue is closed
+    pub(crate) fn pop(&self) -> Option<R> {
+        match self.wait(None) {
+            Pop::Request(req) => Some(req),
+            Pop::TimedOut | Pop::Closed => None,
+        }
+    }
+
+    /// Wait for the hottest request for at most `timeout`
+    pub(crate) fn pop_timeout(&self, timeout: Duration) -> Pop<R> {
+        self.wait(Some(Instant::now() + timeout))
+    }
+
+    fn wait(&self, deadline: Option<Instant>) -> Pop<R> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Pop::Closed;
+            }
+
+            let hottest = state
+                .pending
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.rate.total_cmp(&b.rate).then(b.seq.cmp(&a.seq)))
+                .map(|(idx, _)| idx);
+            if let Some(idx) = hottest {
+                return Pop::Request(state.pending.swap_remove(idx).req);
+            }
+
+            state = match deadline {
+                None => self
+                    .ready
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Pop::TimedOut;
+                    }
+                    self.ready
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+    }
+
+    /// Take everything queued, hottest first, without waiting
+    #[cfg(test)]
+    pub(crate) fn drain(&self) -> Vec<R> {
+        let mut drained = Vec::new();
+        while !self.lock().pending.is_empty() {
+            drained.extend(self.pop());
+        }
+        drained
+    }
+
+    /// Drop everything queued and wake up every waiting worker
+    pub(crate) fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.pending.clear();
+        self.ready.notify_all();
+    }
+}
+
+impl<R> std::fmt::Debug for RequestQueue<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.lock();
+        f.debug_struct("RequestQueue")
+            .field("pending", &state.pending.len())
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn hottest_first() {
+        let queue = RequestQueue::new();
+        queue.push_with_rate("cold", 0.0);
+        queue.push_with_rate("warm", 10.0);
+        queue.push_with_rate("hot", 1000.0);
+        queue.push_with_rate("warm again", 10.0);
+
+        let order: Vec<_> = (0..4).map(|_| queue.pop().unwrap()).collect();
+        assert_eq!(order, ["hot", "warm", "warm again", "cold"]);
+    }
+
+    #[test]
+    fn unprofiled_in_queued_order() {
+        let queue = RequestQueue::new();
+        for req in 0..5 {
+            queue.push_with_rate(req, 0.0);
+        }
+        let order: Vec<_> = (0..5).map(|_| queue.pop().unwrap()).collect();
+        assert_eq!(order, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn closed_queue_drops_requests() {
+        let queue = RequestQueue::new();
+        queue.push_with_rate(1, 1.0);
+        queue.close();
+        assert_eq!(queue.pop(), None);
+
+        queue.push_with_rate(2, 1.0);
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn push_wakes_waiting_worker() {
+        let queue = Arc::new(RequestQueue::new());
+        let worker = {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || queue.pop())
+        };
+        queue.push_with_rate(7, 1.0);
+        assert_eq!(worker.join().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn close_wakes_waiting_workers() {
+        let queue = Arc::new(RequestQueue::<u32>::new());
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                std::thread::spawn(move || queue.pop())
+            })
+            .collect();
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        queue.close();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn pop_timeout_gives_up() {
+        let queue = RequestQueue::new();
+        let timeout = Duration::from_millis(20);
+        let started = Instant::now();
+        assert_eq!(queue.pop_timeout(timeout), Pop::TimedOut);
+        assert!(started.elapsed() >= timeout);
+
+        queue.push_with_rate(3, 1.0);
+        assert_eq!(queue.pop_timeout(timeout), Pop::Request(3));
+        queue.close();
+        assert_eq!(queue.pop_timeout(timeout), Pop::Closed);
+    }
+}
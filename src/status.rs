@@ -0,0 +1,108 @@
+//! Snapshots of what every function and group is doing, see [`status`](crate::status)
+
+use crate::PgoState;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// A `#[pogo]` function linked into the binary
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FunctionStatus {
+    /// Path of the function starting with its crate, `Type::method` for methods
+    pub name: String,
+    /// Whether it was passed to [`init`](crate::init), directly or through
+    /// [`init_all`](crate::init_all)
+    pub initialized: bool,
+    pub groups: Vec<GroupStatus>,
+}
+
+/// One optimization group of a function
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GroupStatus {
+    pub name: String,
+    pub state: StateKind,
+    /// Calls counted while gathering profiling data
+    pub call_count: usize,
+    /// Calls to profile before building the optimized library
+    pub threshold: usize,
+    /// When the group last entered each state
+    pub entered: StateTimes,
+    /// How long building the instrumented library took
+    pub instrument_duration: Option<Duration>,
+    /// How long merging the profile and building the optimized library took,
+    /// `None` when it was loaded from the cache
+    pub optimize_duration: Option<Duration>,
+    pub instrumented_library: Option<PathBuf>,
+    pub optimized_library: Option<PathBuf>,
+    /// Calls whose result matched the natively compiled function while
+    /// verifying the optimized library
+    pub verified_count: usize,
+    /// Why the group failed to compile or its optimized library was rejected
+    pub last_error: Option<String>,
+}
+
+/// [`PgoState`] without the loaded library
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StateKind {
+    Uninitialized,
+    GatheringData,
+    Compiling,
+    Verifying,
+    Optimized,
+    CompilationFailed,
+    Rejected,
+}
+
+impl From<&PgoState> for StateKind {
+    fn from(state: &PgoState) -> StateKind {
+        match state {
+            PgoState::Uninitialized => StateKind::Uninitialized,
+            PgoState::GatheringData(_) => StateKind::GatheringData,
+            PgoState::Compiling(_) => StateKind::Compiling,
+            PgoState::Verifying(_) => StateKind::Verifying,
+            PgoState::Optimized(_) => StateKind::Optimized,
+            PgoState::CompilationFailed(_) => StateKind::CompilationFailed,
+            PgoState::Rejected(_) => StateKind::Rejected,
+        }
+    }
+}
+
+/// The last time a group entered each state, `None` for states it never was in
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateTimes {
+    pub uninitialized: Option<SystemTime>,
+    pub gathering_data: Option<SystemTime>,
+    pub compiling: Option<SystemTime>,
+    pub verifying: Option<SystemTime>,
+    pub optimized: Option<SystemTime>,
+    pub compilation_failed: Option<SystemTime>,
+    pub rejected: Option<SystemTime>,
+}
+
+impl StateTimes {
+    pub(crate) fn entered(&mut self, state: StateKind, at: SystemTime) {
+        let time = match state {
+            StateKind::Uninitialized => &mut self.uninitialized,
+            StateKind::GatheringData => &mut self.gathering_data,
+            StateKind::Compiling => &mut self.compiling,
+            StateKind::Verifying => &mut self.verifying,
+            StateKind::Optimized => &mut self.optimized,
+            StateKind::CompilationFailed => &mut self.compilation_failed,
+            StateKind::Rejected => &mut self.rejected,
+        };
+        *time = Some(at);
+    }
+}
+
+/// What the worker recorded about a group besides its state
+#[derive(Debug, Default)]
+pub(crate) struct GroupStats {
+    pub(crate) entered: StateTimes,
+    pub(crate) instrument_duration: Option<Duration>,
+    pub(crate) optimize_duration: Option<Duration>,
+    pub(crate) instrumented_library: Option<PathBuf>,
+    pub(crate) optimized_library: Option<PathBuf>,
+}
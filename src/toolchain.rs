@@ -0,0 +1,248 @@
+//! Finding the tools the default backend needs and checking they work together
+//!
+//! `rustc` writes raw profiles in the format of the LLVM it was built with, and
+//! `llvm-profdata` can only merge the formats of its own LLVM version. A
+//! mismatch means every optimization would fail, so this is checked up front.
+
+use crate::{ConfigError, PogoConfig};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The tools found by [`check_toolchain`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ToolchainReport {
+    pub rustc: PathBuf,
+    /// Release of rustc, like `1.70.0`
+    pub rustc_release: String,
+    pub rustc_commit_hash: String,
+    /// Target triple rustc runs on and compiles the libraries for
+    pub host: String,
+    pub sysroot: PathBuf,
+    /// Version of the LLVM rustc was built with
+    pub llvm_version: String,
+    pub llvm_profdata: PathBuf,
+    /// Version of the LLVM `llvm_profdata` was built with
+    pub llvm_profdata_version: String,
+}
+
+impl fmt::Display for ToolchainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rustc {} ({}) for {} with LLVM {}, llvm-profdata {} with LLVM {}",
+            self.rustc_release,
+            self.rustc.display(),
+            self.host,
+            self.llvm_version,
+            self.llvm_profdata.display(),
+            self.llvm_profdata_version
+        )
+    }
+}
+
+/// Why the toolchain can't be used
+#[derive(Debug)]
+pub enum ToolchainError {
+    /// A `POGO_*` variable couldn't be applied to the config
+    InvalidConfig(ConfigError),
+    /// `rustc` couldn't be run at all
+    RustcNotFound {
+        rustc: PathBuf,
+        error: std::io::Error,
+    },
+    /// `rustc` ran but its output couldn't be understood
+    UnexpectedOutput { command: String, output: String },
+    /// No `llvm-profdata` was found at any of the searched paths
+    LlvmProfdataNotFound { searched: Vec<PathBuf> },
+    /// `llvm-profdata` was found but can't read the profiles rustc writes
+    VersionMismatch {
+        llvm_profdata: PathBuf,
+        rustc_llvm_version: String,
+        llvm_profdata_version: String,
+    },
+}
+
+impl fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToolchainError::InvalidConfig(error) => error.fmt(f),
+            ToolchainError::RustcNotFound { rustc, error } => {
+                write!(f, "could not run {}: {}", rustc.display(), error)
+            }
+            ToolchainError::UnexpectedOutput { command, output } => {
+                write!(f, "unexpected output from {}: {}", command, output)
+            }
+            ToolchainError::LlvmProfdataNotFound { searched } => {
+                write!(f, "llvm-profdata not found, searched")?;
+                for path in searched {
+                    write!(f, " {}", path.display())?;
+                }
+                write!(
+                    f,
+                    " (install it with `rustup component add llvm-tools-preview`)"
+                )
+            }
+            ToolchainError::VersionMismatch {
+                llvm_profdata,
+                rustc_llvm_version,
+                llvm_profdata_version,
+            } => write!(
+                f,
+                "{} uses LLVM {} but rustc uses LLVM {}, their profile formats don't match",
+                llvm_profdata.display(),
+                llvm_profdata_version,
+                rustc_llvm_version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ToolchainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ToolchainError::InvalidConfig(error) => Some(error),
+            ToolchainError::RustcNotFound { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Find the `rustc` and matching `llvm-profdata` the default backend uses
+/// with `config`
+///
+/// The `POGO_*` variables are applied on top of `config` first, so this is
+/// what [`init`](crate::init) checks for the default backend. Calling it
+/// directly allows reporting why PGO won't be available without initializing
+/// anything.
+pub fn check_toolchain(config: &PogoConfig) -> Result<ToolchainReport, ToolchainError> {
+    let config = config
+        .clone()
+        .apply_env()
+        .map_err(ToolchainError::InvalidConfig)?;
+    config.rustc_backend().toolchain().cloned()
+}
+
+/// Check `rustc` and find `llvm-profdata`, unless a specific one is given
+///
+/// `llvm-profdata` is looked for in rustup's `llvm-tools` component of the
+/// toolchain first, then as `llvm-profdata-<major>` and `llvm-profdata` on the
+/// `PATH`. The first one with the same LLVM major version as rustc is used.
+pub(crate) fn discover(
+    rustc: &Path,
+    llvm_profdata: Option<&Path>,
+) -> Result<ToolchainReport, ToolchainError> {
+    let verbose_version = run(Command::new(rustc).arg("-vV"))
+        .map_err(|error| ToolchainError::RustcNotFound {
+            rustc: rustc.to_path_buf(),
+            error,
+        })?
+        .ok_or_else(|| unexpected_output(rustc, "-vV", ""))?;
+    let field = |name: &str| {
+        verbose_version
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .map(|value| value.trim().to_string())
+            .ok_or_else(|| unexpected_output(rustc, "-vV", &verbose_version))
+    };
+    let rustc_release = field("release")?;
+    let rustc_commit_hash = field("commit-hash")?;
+    let host = field("host")?;
+    let llvm_version = field("LLVM version")?;
+
+    let sysroot = run(Command::new(rustc).args(["--print", "sysroot"]))
+        .ok()
+        .flatten()
+        .map(|sysroot| PathBuf::from(sysroot.trim()))
+        .ok_or_else(|| unexpected_output(rustc, "--print sysroot", ""))?;
+
+    let major = major_version(&llvm_version);
+    let candidates = match llvm_profdata {
+        Some(path) => vec![path.to_path_buf()],
+        None => {
+            let mut candidates = vec![sysroot
+                .join("lib")
+                .join("rustlib")
+                .join(&host)
+                .join("bin")
+                .join(exe_name("llvm-profdata"))];
+            candidates.extend(find_in_path(&exe_name(&format!("llvm-profdata-{}", major))));
+            candidates.extend(find_in_path(&exe_name("llvm-profdata")));
+            candidates
+        }
+    };
+
+    let mut mismatch = None;
+    for candidate in &candidates {
+        let version = match llvm_profdata_version(candidate) {
+            Some(version) => version,
+            None => continue,
+        };
+
+        if major_version(&version) == major {
+            return Ok(ToolchainReport {
+                rustc: rustc.to_path_buf(),
+                rustc_release,
+                rustc_commit_hash,
+                host,
+                sysroot,
+                llvm_version,
+                llvm_profdata: candidate.clone(),
+                llvm_profdata_version: version,
+            });
+        }
+
+        mismatch.get_or_insert(ToolchainError::VersionMismatch {
+            llvm_profdata: candidate.clone(),
+            rustc_llvm_version: llvm_version.clone(),
+            llvm_profdata_version: version,
+        });
+    }
+
+    Err(mismatch.unwrap_or(ToolchainError::LlvmProfdataNotFound {
+        searched: candidates,
+    }))
+}
+
+/// LLVM version of an `llvm-profdata`, or `None` if it can't be run
+fn llvm_profdata_version(path: &Path) -> Option<String> {
+    // Older versions only accept `--version` after a subcommand
+    let output = run(Command::new(path).arg("--version"))
+        .ok()
+        .flatten()
+        .or_else(|| run(Command::new(path).args(["merge", "--version"])).ok()?)?;
+
+    let (_, version) = output.split_once("LLVM version ")?;
+    version.split_whitespace().next().map(str::to_string)
+}
+
+/// Run a command, returning its stdout if it succeeded
+fn run(cmd: &mut Command) -> std::io::Result<Option<String>> {
+    let output = cmd.output()?;
+    if output.status.success() {
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    } else {
+        Ok(None)
+    }
+}
+
+fn unexpected_output(rustc: &Path, args: &str, output: &str) -> ToolchainError {
+    ToolchainError::UnexpectedOutput {
+        command: format!("{} {}", rustc.display(), args),
+        output: output.to_string(),
+    }
+}
+
+fn major_version(version: &str) -> &str {
+    version.split('.').next().unwrap_or(version)
+}
+
+fn exe_name(name: &str) -> String {
+    format!("{}{}", name, std::env::consts::EXE_SUFFIX)
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,47 @@
+//! Runtime messages, sent through `tracing` or `log` depending on which feature
+//! is enabled and dropped without either
+//!
+//! `tracing` wins when both are enabled, its `log` feature forwards events to a
+//! `log` logger if that's still needed.
+
+/// Emit an event at `$level` with the given fields, all recorded with their
+/// `Debug` formatting
+macro_rules! event {
+    ($level:ident, $($key:ident = $value:expr),+; $message:literal) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($key = ?$value),+, $message);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        ::log::$level!(target: "pogo", $($key:? = $value),+; $message);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        {
+            $(let _ = &$value;)+
+        }
+    }};
+}
+
+/// Enter a span covering one compile phase of a group, a no-op without
+/// `tracing`
+macro_rules! phase_span {
+    ($phase:literal, $function:expr, $group:expr) => {{
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::info_span!(
+            "pogo_phase",
+            phase = $phase,
+            function = $function,
+            group = $group
+        )
+        .entered();
+        #[cfg(not(feature = "tracing"))]
+        let span = {
+            let _ = (&$function, &$group);
+            $crate::trace::NoSpan
+        };
+        span
+    }};
+}
+
+/// What [`phase_span`] returns without `tracing`
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+pub(crate) use {event, phase_span};